@@ -0,0 +1,301 @@
+use miette::{IntoDiagnostic, Result, bail, miette};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    cache::ssh::{PathInfo, read_string, read_strings, read_u64, write_string, write_u64},
+    store::path::StorePath,
+};
+
+// the worker protocol of the Nix daemon, which is what Nix uses for ssh-ng:// stores
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+// 1.35, which is before features were negotiated in the handshake
+const PROTOCOL_VERSION: u64 = 0x123;
+
+const OP_QUERY_PATH_INFO: u64 = 26;
+const OP_NAR_FROM_PATH: u64 = 38;
+
+const STDERR_NEXT: u64 = 0x6f6c6d67;
+const STDERR_READ: u64 = 0x64617461;
+const STDERR_WRITE: u64 = 0x64617416;
+const STDERR_LAST: u64 = 0x616c7473;
+const STDERR_ERROR: u64 = 0x63787470;
+const STDERR_START_ACTIVITY: u64 = 0x53545254;
+const STDERR_STOP_ACTIVITY: u64 = 0x53544f50;
+const STDERR_RESULT: u64 = 0x52534c54;
+
+fn minor(version: u64) -> u64 {
+    version & 0xff
+}
+
+// returns the protocol version used by both sides
+pub async fn handshake(
+    r: &mut (impl AsyncRead + Unpin),
+    w: &mut (impl AsyncWrite + Unpin),
+) -> Result<u64> {
+    write_u64(w, WORKER_MAGIC_1).await?;
+    w.flush().await.into_diagnostic()?;
+
+    if read_u64(r).await? != WORKER_MAGIC_2 {
+        bail!("protocol mismatch");
+    }
+    let daemon = read_u64(r).await?;
+    // signatures and content addresses of path infos were added in 1.16
+    if daemon & 0xff00 != 0x100 || minor(daemon) < 16 {
+        bail!("unsupported protocol version {daemon:#x}");
+    }
+    let version = daemon.min(PROTOCOL_VERSION);
+
+    write_u64(w, PROTOCOL_VERSION).await?;
+    // no cpu affinity and no reserved space
+    write_u64(w, 0).await?;
+    write_u64(w, 0).await?;
+    w.flush().await.into_diagnostic()?;
+
+    if minor(version) >= 33 {
+        let _nix_version = read_string(r).await?;
+    }
+    if minor(version) >= 35 {
+        let _trusted = read_u64(r).await?;
+    }
+    process_stderr(r, version).await?;
+
+    Ok(version)
+}
+
+pub async fn query_path_info(
+    r: &mut (impl AsyncRead + Unpin),
+    w: &mut (impl AsyncWrite + Unpin),
+    version: u64,
+    path: &StorePath,
+) -> Result<Option<String>> {
+    write_u64(w, OP_QUERY_PATH_INFO).await?;
+    write_string(w, &format!("/nix/store/{path}")).await?;
+    w.flush().await.into_diagnostic()?;
+    process_stderr(r, version).await?;
+
+    if read_u64(r).await? == 0 {
+        return Ok(None);
+    }
+
+    let deriver = read_string(r).await?;
+    // nar hashes are sent as base16 sha256 without the algorithm
+    let nar_hash = format!("sha256:{}", read_string(r).await?);
+    let references = read_strings(r).await?;
+    let _registration_time = read_u64(r).await?;
+    let nar_size = read_u64(r).await?;
+    let _ultimate = read_u64(r).await?;
+    let sigs = read_strings(r).await?;
+    let ca = read_string(r).await?;
+
+    PathInfo {
+        deriver,
+        references,
+        nar_size,
+        nar_hash,
+        ca,
+        sigs,
+    }
+    .to_narinfo(&format!("/nix/store/{path}"), path)
+    .map(Some)
+}
+
+// the nar is sent after the last stderr message, and the daemon exits once stdin is closed,
+// so the rest of the output is the nar
+pub async fn nar_from_path(
+    r: &mut (impl AsyncRead + Unpin),
+    w: &mut (impl AsyncWrite + Unpin),
+    version: u64,
+    path: &StorePath,
+) -> Result<()> {
+    write_u64(w, OP_NAR_FROM_PATH).await?;
+    write_string(w, &format!("/nix/store/{path}")).await?;
+    w.flush().await.into_diagnostic()?;
+    process_stderr(r, version).await
+}
+
+// logs from the daemon are skipped until it is done with the operation
+async fn process_stderr(r: &mut (impl AsyncRead + Unpin), version: u64) -> Result<()> {
+    loop {
+        match read_u64(r).await? {
+            STDERR_LAST => return Ok(()),
+            STDERR_NEXT | STDERR_WRITE => {
+                read_string(r).await?;
+            }
+            STDERR_START_ACTIVITY => {
+                let _id = read_u64(r).await?;
+                let _level = read_u64(r).await?;
+                let _type = read_u64(r).await?;
+                read_string(r).await?;
+                read_fields(r).await?;
+                let _parent = read_u64(r).await?;
+            }
+            STDERR_STOP_ACTIVITY => {
+                let _id = read_u64(r).await?;
+            }
+            STDERR_RESULT => {
+                let _id = read_u64(r).await?;
+                let _type = read_u64(r).await?;
+                read_fields(r).await?;
+            }
+            STDERR_ERROR if minor(version) >= 26 => {
+                let _type = read_string(r).await?;
+                let _level = read_u64(r).await?;
+                let _name = read_string(r).await?;
+                let msg = read_string(r).await?;
+                let _pos = read_u64(r).await?;
+                for _ in 0 .. read_u64(r).await? {
+                    let _pos = read_u64(r).await?;
+                    read_string(r).await?;
+                }
+                return Err(miette!("{msg}"));
+            }
+            STDERR_ERROR => {
+                let msg = read_string(r).await?;
+                let _status = read_u64(r).await?;
+                return Err(miette!("{msg}"));
+            }
+            STDERR_READ => bail!("the daemon unexpectedly requested data"),
+            msg => bail!("unknown message from the daemon: {msg:#x}"),
+        }
+    }
+}
+
+// integers and strings attached to activities
+async fn read_fields(r: &mut (impl AsyncRead + Unpin)) -> Result<()> {
+    for _ in 0 .. read_u64(r).await? {
+        match read_u64(r).await? {
+            0 => {
+                read_u64(r).await?;
+            }
+            1 => {
+                read_string(r).await?;
+            }
+            ty => bail!("unknown field type {ty}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, split},
+        spawn,
+    };
+
+    use super::{
+        OP_NAR_FROM_PATH, OP_QUERY_PATH_INFO, STDERR_ERROR, STDERR_LAST, STDERR_NEXT,
+        STDERR_START_ACTIVITY, WORKER_MAGIC_1, WORKER_MAGIC_2, handshake, nar_from_path,
+        query_path_info,
+    };
+    use crate::{
+        cache::ssh::{read_string, read_u64, write_string, write_u64},
+        store::path::StorePath,
+    };
+
+    #[tokio::test]
+    async fn protocol() {
+        let (client, server) = duplex(4096);
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let glibc = "/nix/store/5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66";
+
+        // a minimal nix-daemon --stdio
+        let server = spawn(async move {
+            let (mut r, mut w) = split(server);
+            assert_eq!(read_u64(&mut r).await.unwrap(), WORKER_MAGIC_1);
+            write_u64(&mut w, WORKER_MAGIC_2).await.unwrap();
+            write_u64(&mut w, 0x125).await.unwrap();
+            assert_eq!(read_u64(&mut r).await.unwrap(), 0x123);
+            read_u64(&mut r).await.unwrap();
+            read_u64(&mut r).await.unwrap();
+            write_string(&mut w, "2.24.0").await.unwrap();
+            write_u64(&mut w, 2).await.unwrap();
+            write_u64(&mut w, STDERR_LAST).await.unwrap();
+
+            assert_eq!(read_u64(&mut r).await.unwrap(), OP_QUERY_PATH_INFO);
+            let name = read_string(&mut r).await.unwrap();
+            assert!(name.ends_with("hello-2.12.1"));
+            write_u64(&mut w, STDERR_NEXT).await.unwrap();
+            write_string(&mut w, "querying").await.unwrap();
+            write_u64(&mut w, STDERR_START_ACTIVITY).await.unwrap();
+            for n in [1, 0, 0] {
+                write_u64(&mut w, n).await.unwrap();
+            }
+            write_string(&mut w, "").await.unwrap();
+            write_u64(&mut w, 1).await.unwrap();
+            write_u64(&mut w, 1).await.unwrap();
+            write_string(&mut w, "field").await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+            write_u64(&mut w, STDERR_LAST).await.unwrap();
+            write_u64(&mut w, 1).await.unwrap();
+            write_string(
+                &mut w,
+                "/nix/store/gciipqhqkdlqqn803zd4a389v86ran45-hello.drv",
+            )
+            .await
+            .unwrap();
+            write_string(
+                &mut w,
+                "3a1a5a6c6f7d4fd2b3d50d7d9d70c8d0f0b1e8c1d8f3f0ac9fb1d94a6fbd3b1f",
+            )
+            .await
+            .unwrap();
+            write_u64(&mut w, 1).await.unwrap();
+            write_string(&mut w, glibc).await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+            write_u64(&mut w, 226560).await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+            write_u64(&mut w, 1).await.unwrap();
+            write_string(&mut w, "test-1:c2ln").await.unwrap();
+            write_string(&mut w, "").await.unwrap();
+
+            assert_eq!(read_u64(&mut r).await.unwrap(), OP_QUERY_PATH_INFO);
+            read_string(&mut r).await.unwrap();
+            write_u64(&mut w, STDERR_LAST).await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+
+            assert_eq!(read_u64(&mut r).await.unwrap(), OP_NAR_FROM_PATH);
+            read_string(&mut r).await.unwrap();
+            write_u64(&mut w, STDERR_ERROR).await.unwrap();
+            write_string(&mut w, "Error").await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+            write_string(&mut w, "Error").await.unwrap();
+            write_string(&mut w, "path is not valid").await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+            write_u64(&mut w, 0).await.unwrap();
+        });
+
+        let (mut r, mut w) = split(client);
+        let version = handshake(&mut r, &mut w).await.unwrap();
+        assert_eq!(version, 0x123);
+        assert_eq!(
+            query_path_info(&mut r, &mut w, version, &path)
+                .await
+                .unwrap()
+                .unwrap(),
+            format!(
+                "StorePath: /nix/store/{path}\nURL: {path}\nCompression: none\n\
+                 NarHash: sha256:07rvpmplmndikyng1wyqq7lb3w6hr1q9sz8dsnrx4kvxdxn5l6is\n\
+                 NarSize: 226560\nReferences: 5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66\n\
+                 Deriver: gciipqhqkdlqqn803zd4a389v86ran45-hello.drv\nSig: test-1:c2ln\n",
+            ),
+        );
+
+        let missing =
+            StorePath::from_storeless("00000000000000000000000000000000-missing").unwrap();
+        assert!(
+            query_path_info(&mut r, &mut w, version, &missing)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let e = nar_from_path(&mut r, &mut w, version, &missing)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "path is not valid");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::ErrorKind;
+
+use camino::Utf8PathBuf;
+use miette::{IntoDiagnostic, Result, WrapErr, miette};
+use tokio::{
+    fs::{File, read_to_string},
+    io::BufReader,
+};
+use url::Url;
+
+use crate::cache::NarReader;
+
+pub async fn get_file(cache: &Url, path: &str) -> Result<Option<String>> {
+    let path = to_path(cache, path)?;
+    match read_to_string(&path).await {
+        Ok(narinfo) => Ok(Some(narinfo)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read {path}")),
+    }
+}
+
+pub async fn get_nar(cache: &Url, url: &str) -> Result<NarReader> {
+    let path = to_path(cache, url)?;
+    let file = File::open(&path)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to open {path}"))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+fn to_path(cache: &Url, url: &str) -> Result<Utf8PathBuf> {
+    let url = cache.join(url).into_diagnostic()?;
+    let path = url
+        .to_file_path()
+        .map_err(|_| miette!("invalid file url: {url}"))?;
+    Utf8PathBuf::try_from(path).into_diagnostic()
+}
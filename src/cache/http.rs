@@ -0,0 +1,117 @@
+use std::{
+    env::{self, VarError},
+    io,
+};
+
+use miette::{IntoDiagnostic, Result, WrapErr};
+use reqwest::StatusCode;
+use reqwest_middleware::RequestBuilder;
+use tokio_stream::StreamExt;
+use tokio_util::io::StreamReader;
+
+use crate::{
+    cache::{Cache, NarReader, netrc, s3},
+    state::HTTP_CLIENT,
+};
+
+pub async fn get_file(cache: &Cache, path: &str) -> Result<Option<String>> {
+    let res = get(cache, path)?.send().await.into_diagnostic()?;
+
+    if res.status() == StatusCode::NOT_FOUND {
+        Ok(None)
+    } else {
+        Ok(Some(
+            res.error_for_status()
+                .into_diagnostic()?
+                .text()
+                .await
+                .into_diagnostic()?,
+        ))
+    }
+}
+
+pub async fn get_nar(cache: &Cache, url: &str) -> Result<NarReader> {
+    let body = get(cache, url)?
+        .send()
+        .await
+        .into_diagnostic()?
+        .error_for_status()
+        .into_diagnostic()?
+        .bytes_stream()
+        .map(|chunk| chunk.map_err(io::Error::other));
+
+    Ok(Box::new(StreamReader::new(body)))
+}
+
+fn get(cache: &Cache, url: &str) -> Result<RequestBuilder> {
+    get_with_env(cache, url, |name| env::var(name))
+}
+
+// nar urls can point to other hosts, which should not receive the token of the cache
+// the environment is passed in, so tests don't need to modify the environment of the process
+fn get_with_env(
+    cache: &Cache,
+    url: &str,
+    var: impl FnOnce(&str) -> Result<String, VarError>,
+) -> Result<RequestBuilder> {
+    let mut url = cache.url.join(url).into_diagnostic()?;
+    if url.scheme() == "s3" {
+        // options of s3 caches are in the query string
+        url.set_query(cache.url.query());
+        return s3::get(&url);
+    }
+    let host = url.host_str();
+
+    if let Some(name) = &cache.token_env
+        && host == cache.url.host_str()
+    {
+        let token = var(name)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read the token for {cache} from {name}"))?;
+        Ok(HTTP_CLIENT.get(url).bearer_auth(token))
+    } else if let Some(creds) = host.and_then(netrc::get) {
+        Ok(HTTP_CLIENT
+            .get(url)
+            .basic_auth(&creds.login, Some(&creds.password)))
+    } else {
+        Ok(HTTP_CLIENT.get(url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::VarError;
+
+    use reqwest::header::AUTHORIZATION;
+    use tokio::sync::OnceCell;
+
+    use super::get_with_env;
+    use crate::cache::{Cache, parse_url};
+
+    #[test]
+    fn token() {
+        let cache = Cache {
+            url: parse_url("https://cache.example.org").unwrap(),
+            public_keys: Vec::new(),
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: Some("UNNIX_TEST_TOKEN".into()),
+            info: OnceCell::new(),
+        };
+        let get = |url| {
+            get_with_env(&cache, url, |name| match name {
+                "UNNIX_TEST_TOKEN" => Ok("secret".into()),
+                _ => Err(VarError::NotPresent),
+            })
+        };
+
+        let req = get("nar/foo.nar").unwrap().build().unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer secret");
+        let req = get("https://nar.example.org/foo.nar")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(req.headers().get(AUTHORIZATION).is_none());
+    }
+}
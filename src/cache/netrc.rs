@@ -0,0 +1,165 @@
+use std::{collections::HashMap, env, fs::read_to_string, io::ErrorKind, sync::OnceLock};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use dirs::home_dir;
+use miette::{IntoDiagnostic, Result, WrapErr, bail};
+use tracing::warn;
+
+static NETRC: OnceLock<Netrc> = OnceLock::new();
+
+// the same format as the netrc-file Nix setting,
+// passwords are deliberately kept out of Debug
+#[derive(Default)]
+struct Netrc {
+    machines: HashMap<String, Credentials>,
+    default: Option<Credentials>,
+}
+
+#[derive(Default)]
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+}
+
+// falls back to $NETRC and then ~/.netrc like curl when no netrc file is specified
+pub fn load(path: Option<&Utf8Path>) -> Result<()> {
+    let path = match path {
+        Some(path) => path.to_owned(),
+        None => match env::var("NETRC") {
+            Ok(path) => path.into(),
+            Err(_) => {
+                load_home();
+                return Ok(());
+            }
+        },
+    };
+
+    let content = read_to_string(&path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to read {path}"))?;
+    let netrc = parse(&content).wrap_err_with(|| format!("failed to parse {path}"))?;
+    let _ = NETRC.set(netrc);
+    Ok(())
+}
+
+// ~/.netrc is shared with other programs, so a file unnix can't use is skipped with a warning
+fn load_home() {
+    let Some(Ok(path)) = home_dir().map(|home| Utf8PathBuf::try_from(home.join(".netrc"))) else {
+        return;
+    };
+    let content = match read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("failed to read {path}: {e}");
+            return;
+        }
+    };
+    match parse(&content) {
+        Ok(netrc) => {
+            let _ = NETRC.set(netrc);
+        }
+        Err(e) => {
+            warn!("failed to parse {path}: {e}");
+        }
+    }
+}
+
+pub fn get(host: &str) -> Option<&'static Credentials> {
+    let netrc = NETRC.get()?;
+    netrc.machines.get(host).or(netrc.default.as_ref())
+}
+
+fn parse(content: &str) -> Result<Netrc> {
+    // machine names are None for default
+    let mut entries: Vec<(Option<&str>, Credentials)> = Vec::new();
+    let mut lines = content.lines().enumerate();
+
+    while let Some((i, line)) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    let Some(machine) = tokens.next() else {
+                        bail!("expected a machine name");
+                    };
+                    entries.push((Some(machine), Credentials::default()));
+                }
+                "default" => {
+                    entries.push((None, Credentials::default()));
+                }
+                "login" | "password" | "account" => {
+                    let Some(value) = tokens.next() else {
+                        bail!("expected a value for {token}");
+                    };
+                    let Some((_, creds)) = entries.last_mut() else {
+                        bail!("{token} must come after machine or default");
+                    };
+                    match token {
+                        "login" => creds.login = value.into(),
+                        "password" => creds.password = value.into(),
+                        _ => {}
+                    }
+                }
+                // macros span until the next empty line
+                "macdef" => {
+                    for (_, line) in lines.by_ref() {
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                // the token itself is not shown, since it could be a password
+                _ => {
+                    bail!("unexpected token on line {}", i + 1);
+                }
+            }
+        }
+    }
+
+    // the first matching entry wins, like in curl
+    let mut netrc = Netrc::default();
+    for (machine, creds) in entries {
+        match machine {
+            Some(machine) => {
+                netrc.machines.entry(machine.into()).or_insert(creds);
+            }
+            None => {
+                netrc.default.get_or_insert(creds);
+            }
+        }
+    }
+    Ok(netrc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn basic() {
+        let netrc = parse(
+            "machine cache.example.org login alice password hunter2
+machine attic.example.org
+  login bob
+  password secret
+macdef init
+  cd /
+  ls
+
+default login anonymous password guest",
+        )
+        .unwrap();
+
+        let creds = &netrc.machines["cache.example.org"];
+        assert_eq!((&*creds.login, &*creds.password), ("alice", "hunter2"));
+        let creds = &netrc.machines["attic.example.org"];
+        assert_eq!((&*creds.login, &*creds.password), ("bob", "secret"));
+        let creds = netrc.default.as_ref().unwrap();
+        assert_eq!((&*creds.login, &*creds.password), ("anonymous", "guest"));
+
+        assert!(parse("machine").is_err());
+        assert!(parse("machine foo token bar").is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    fmt::Write,
+    future::ready,
+    io,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    sync::LazyLock,
+    task::{Context, Poll, ready},
+};
+
+use dashmap::DashMap;
+use harmonia_utils_hash::{
+    Hash,
+    fmt::{Any, CommonHash},
+};
+use miette::{IntoDiagnostic, Result, WrapErr, bail};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+use url::Url;
+
+use crate::{
+    cache::{NarReader, daemon},
+    store::path::StorePath,
+};
+
+// the protocol of `nix-store --serve`, which is what Nix uses for ssh:// stores
+const SERVE_MAGIC_1: u64 = 0x390c9deb;
+const SERVE_MAGIC_2: u64 = 0x5452eecb;
+const SERVE_PROTOCOL_VERSION: u64 = 0x205;
+const CMD_QUERY_PATH_INFOS: u64 = 2;
+const CMD_DUMP_STORE_PATH: u64 = 3;
+
+// idle connections for narinfo queries, keyed by cache
+static POOL: LazyLock<DashMap<Url, Vec<Connection>>> = LazyLock::new(DashMap::new);
+
+struct Connection {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    protocol: Protocol,
+}
+
+enum Protocol {
+    // nix-store --serve for ssh://
+    Serve,
+    // nix-daemon --stdio for ssh-ng://, with the negotiated version
+    Daemon(u64),
+}
+
+// keeps ssh running until the nar is fully read, and fails at eof if ssh did not exit cleanly
+struct NarStream {
+    stdout: BufReader<ChildStdout>,
+    // None once ssh has exited successfully
+    status: Option<Pin<Box<dyn Future<Output = io::Result<ExitStatus>> + Send>>>,
+}
+
+// neither nix-store --serve nor nix-daemon has a nix-cache-info,
+// and caches listed explicitly are expected to have most paths
+pub const NIX_CACHE_INFO: &str = "StoreDir: /nix/store\nWantMassQuery: 1\n";
+
+// the path info is converted to a narinfo, so it goes through the same signature checks
+pub async fn get_narinfo(cache: &Url, path: &StorePath) -> Result<Option<String>> {
+    // the pool must not be locked while connecting
+    let conn = POOL.get_mut(cache).and_then(|mut conns| conns.pop());
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => connect(cache).await?,
+    };
+
+    let narinfo = match conn.protocol {
+        Protocol::Serve => query_path_info(&mut conn.stdout, &mut conn.stdin, path).await,
+        Protocol::Daemon(version) => {
+            daemon::query_path_info(&mut conn.stdout, &mut conn.stdin, version, path).await
+        }
+    }
+    .wrap_err_with(|| format!("failed to query {path} on {cache}"))?;
+    POOL.entry(cache.clone()).or_default().push(conn);
+
+    Ok(narinfo)
+}
+
+pub async fn get_nar(cache: &Url, url: &str) -> Result<NarReader> {
+    let path = StorePath::from_storeless(url)?;
+    let Connection {
+        _child: mut child,
+        mut stdin,
+        mut stdout,
+        protocol,
+    } = connect(cache).await?;
+
+    match protocol {
+        Protocol::Serve => {
+            write_u64(&mut stdin, CMD_DUMP_STORE_PATH).await?;
+            write_string(&mut stdin, &format!("/nix/store/{path}")).await?;
+            stdin.flush().await.into_diagnostic()?;
+        }
+        Protocol::Daemon(version) => {
+            daemon::nar_from_path(&mut stdout, &mut stdin, version, &path)
+                .await
+                .wrap_err_with(|| format!("failed to download {path} from {cache}"))?;
+        }
+    }
+    // both programs exit after the nar is sent once stdin is closed,
+    // so the nar can be read until eof
+    drop(stdin);
+
+    Ok(Box::new(NarStream {
+        stdout,
+        status: Some(Box::pin(async move { child.wait().await })),
+    }))
+}
+
+// ssh://[user@]host[:port]?ssh-key=...&remote-program=...
+// ssh-ng:// takes the same options
+async fn connect(cache: &Url) -> Result<Connection> {
+    let Some(host) = cache.host_str() else {
+        bail!("missing host in {cache}");
+    };
+    // ssh would parse these as options
+    if host.starts_with('-') || cache.username().starts_with('-') {
+        bail!("invalid ssh destination in {cache}");
+    }
+
+    let mut cmd = Command::new("ssh");
+    cmd.arg("-x").arg("-a");
+    if let Some(port) = cache.port() {
+        cmd.arg("-p").arg(port.to_string());
+    }
+
+    let (mut program, arg) = if cache.scheme() == "ssh-ng" {
+        ("nix-daemon".into(), "--stdio")
+    } else {
+        ("nix-store".into(), "--serve")
+    };
+    for (key, value) in cache.query_pairs() {
+        match &*key {
+            "ssh-key" => {
+                cmd.arg("-i").arg(&*value);
+            }
+            "remote-program" => {
+                program = value.into_owned();
+            }
+            _ => {}
+        }
+    }
+
+    let dest = if cache.username().is_empty() {
+        host.to_owned()
+    } else {
+        format!("{}@{host}", cache.username())
+    };
+    cmd.arg("--").arg(dest).arg(program).arg(arg);
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("failed to run ssh")?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    let protocol = if cache.scheme() == "ssh-ng" {
+        daemon::handshake(&mut stdout, &mut stdin)
+            .await
+            .map(Protocol::Daemon)
+    } else {
+        handshake(&mut stdout, &mut stdin)
+            .await
+            .map(|()| Protocol::Serve)
+    };
+    let protocol = match protocol {
+        Ok(protocol) => protocol,
+        Err(e) => {
+            // ssh closes stdout when it exits, e.g. when the host is unreachable
+            let e = match child.try_wait() {
+                Ok(Some(status)) if !status.success() => {
+                    e.wrap_err(format!("ssh exited with {status}"))
+                }
+                _ => e,
+            };
+            return Err(e.wrap_err(format!("failed to connect to {cache}")));
+        }
+    };
+
+    Ok(Connection {
+        _child: child,
+        stdin,
+        stdout,
+        protocol,
+    })
+}
+
+async fn handshake(
+    r: &mut (impl AsyncRead + Unpin),
+    w: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    write_u64(w, SERVE_MAGIC_1).await?;
+    write_u64(w, SERVE_PROTOCOL_VERSION).await?;
+    w.flush().await.into_diagnostic()?;
+
+    if read_u64(r).await? != SERVE_MAGIC_2 {
+        bail!("protocol mismatch");
+    }
+    let version = read_u64(r).await?;
+    // nar hashes and signatures were added in 2.4
+    if version & 0xff00 != 0x200 || version & 0xff < 4 {
+        bail!("unsupported protocol version {version:#x}");
+    }
+
+    Ok(())
+}
+
+async fn query_path_info(
+    r: &mut (impl AsyncRead + Unpin),
+    w: &mut (impl AsyncWrite + Unpin),
+    path: &StorePath,
+) -> Result<Option<String>> {
+    write_u64(w, CMD_QUERY_PATH_INFOS).await?;
+    write_u64(w, 1).await?;
+    write_string(w, &format!("/nix/store/{path}")).await?;
+    w.flush().await.into_diagnostic()?;
+
+    let mut narinfo = None;
+    loop {
+        let name = read_string(r).await?;
+        if name.is_empty() {
+            break;
+        }
+
+        let deriver = read_string(r).await?;
+        let references = read_strings(r).await?;
+        let _download_size = read_u64(r).await?;
+        let nar_size = read_u64(r).await?;
+        let nar_hash = read_string(r).await?;
+        let ca = read_string(r).await?;
+        let sigs = read_strings(r).await?;
+
+        let content = PathInfo {
+            deriver,
+            references,
+            nar_size,
+            nar_hash,
+            ca,
+            sigs,
+        }
+        .to_narinfo(&name, path)?;
+        narinfo = Some(content);
+    }
+
+    Ok(narinfo)
+}
+
+// the fields that are shared by both protocols
+pub(super) struct PathInfo {
+    pub deriver: String,
+    pub references: Vec<String>,
+    pub nar_size: u64,
+    pub nar_hash: String,
+    pub ca: String,
+    pub sigs: Vec<String>,
+}
+
+impl PathInfo {
+    pub fn to_narinfo(&self, name: &str, path: &StorePath) -> Result<String> {
+        let nar_hash: Hash = self.nar_hash.parse::<Any<_>>().into_diagnostic()?.into();
+        let mut content = format!(
+            "StorePath: {name}\nURL: {path}\nCompression: none\nNarHash: {}\nNarSize: {}\n",
+            nar_hash.as_base32(),
+            self.nar_size,
+        );
+        let references: Vec<_> = self
+            .references
+            .iter()
+            .map(|path| StorePath::new(path).map(|path| path.to_string()))
+            .collect::<Result<_>>()?;
+        writeln!(content, "References: {}", references.join(" ")).into_diagnostic()?;
+        if let Some(deriver) = self.deriver.strip_prefix("/nix/store/") {
+            writeln!(content, "Deriver: {deriver}").into_diagnostic()?;
+        }
+        if !self.ca.is_empty() {
+            writeln!(content, "CA: {}", self.ca).into_diagnostic()?;
+        }
+        for sig in &self.sigs {
+            writeln!(content, "Sig: {sig}").into_diagnostic()?;
+        }
+        Ok(content)
+    }
+}
+
+pub(super) async fn read_u64(r: &mut (impl AsyncRead + Unpin)) -> Result<u64> {
+    r.read_u64_le().await.into_diagnostic()
+}
+
+// strings are padded to multiples of 8 bytes
+pub(super) async fn read_string(r: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let len = read_u64(r).await? as usize;
+    let mut buf = vec![0; len.next_multiple_of(8)];
+    r.read_exact(&mut buf).await.into_diagnostic()?;
+    buf.truncate(len);
+    String::from_utf8(buf).into_diagnostic()
+}
+
+pub(super) async fn read_strings(r: &mut (impl AsyncRead + Unpin)) -> Result<Vec<String>> {
+    let len = read_u64(r).await?;
+    let mut strings = Vec::new();
+    for _ in 0 .. len {
+        strings.push(read_string(r).await?);
+    }
+    Ok(strings)
+}
+
+pub(super) async fn write_u64(w: &mut (impl AsyncWrite + Unpin), n: u64) -> Result<()> {
+    w.write_u64_le(n).await.into_diagnostic()
+}
+
+pub(super) async fn write_string(w: &mut (impl AsyncWrite + Unpin), s: &str) -> Result<()> {
+    write_u64(w, s.len() as u64).await?;
+    w.write_all(s.as_bytes()).await.into_diagnostic()?;
+    w.write_all(&[0; 8][.. s.len().next_multiple_of(8) - s.len()])
+        .await
+        .into_diagnostic()
+}
+
+impl NarStream {
+    fn poll_status(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(status) = &mut self.status else {
+            return Poll::Ready(Ok(()));
+        };
+        let status = ready!(status.as_mut().poll(cx))?;
+        if !status.success() {
+            // keep failing if the reader tries again
+            self.status = Some(Box::pin(ready(Ok(status))));
+            return Poll::Ready(Err(io::Error::other(format!("ssh exited with {status}"))));
+        }
+        self.status = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for NarStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = data.len().min(buf.remaining());
+        buf.put_slice(&data[.. n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for NarStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if ready!(Pin::new(&mut this.stdout).poll_fill_buf(cx))?.is_empty() {
+            ready!(this.poll_status(cx))?;
+        }
+        Pin::new(&mut this.stdout).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.stdout).consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, split},
+        spawn,
+    };
+
+    use super::{
+        SERVE_MAGIC_1, SERVE_MAGIC_2, connect, handshake, query_path_info, read_strings, read_u64,
+        write_string, write_u64,
+    };
+    use crate::{cache::parse_url, store::path::StorePath};
+
+    #[tokio::test]
+    async fn options() {
+        for url in [
+            "ssh://-oProxyCommand=touch&x",
+            "ssh://-oProxyCommand=sh@builder",
+        ] {
+            let e = connect(&parse_url(url).unwrap()).await.err().unwrap();
+            assert!(e.to_string().starts_with("invalid ssh destination"));
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol() {
+        let (client, server) = duplex(4096);
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let glibc = "/nix/store/5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66";
+
+        // a minimal nix-store --serve
+        let server = spawn(async move {
+            let (mut r, mut w) = split(server);
+            assert_eq!(read_u64(&mut r).await.unwrap(), SERVE_MAGIC_1);
+            read_u64(&mut r).await.unwrap();
+            write_u64(&mut w, SERVE_MAGIC_2).await.unwrap();
+            write_u64(&mut w, 0x207).await.unwrap();
+
+            for _ in 0 .. 2 {
+                assert_eq!(read_u64(&mut r).await.unwrap(), 2);
+                let paths = read_strings(&mut r).await.unwrap();
+                if paths[0].ends_with("hello-2.12.1") {
+                    write_string(&mut w, &paths[0]).await.unwrap();
+                    write_string(
+                        &mut w,
+                        "/nix/store/gciipqhqkdlqqn803zd4a389v86ran45-hello.drv",
+                    )
+                    .await
+                    .unwrap();
+                    write_u64(&mut w, 1).await.unwrap();
+                    write_string(&mut w, glibc).await.unwrap();
+                    write_u64(&mut w, 0).await.unwrap();
+                    write_u64(&mut w, 226560).await.unwrap();
+                    write_string(
+                        &mut w,
+                        "sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw",
+                    )
+                    .await
+                    .unwrap();
+                    write_string(&mut w, "").await.unwrap();
+                    write_u64(&mut w, 1).await.unwrap();
+                    write_string(&mut w, "test-1:c2ln").await.unwrap();
+                }
+                write_string(&mut w, "").await.unwrap();
+            }
+        });
+
+        let (mut r, mut w) = split(client);
+        handshake(&mut r, &mut w).await.unwrap();
+        assert_eq!(
+            query_path_info(&mut r, &mut w, &path)
+                .await
+                .unwrap()
+                .unwrap(),
+            format!(
+                "StorePath: /nix/store/{path}\nURL: {path}\nCompression: none\n\
+                 NarHash: sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw\n\
+                 NarSize: 226560\nReferences: 5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66\n\
+                 Deriver: gciipqhqkdlqqn803zd4a389v86ran45-hello.drv\nSig: test-1:c2ln\n",
+            ),
+        );
+
+        let missing =
+            StorePath::from_storeless("00000000000000000000000000000000-missing").unwrap();
+        assert!(
+            query_path_info(&mut r, &mut w, &missing)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        server.await.unwrap();
+    }
+}
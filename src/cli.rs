@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{net::SocketAddr, str::FromStr};
 
 use camino::Utf8PathBuf;
 use clap::{
@@ -32,6 +32,9 @@ pub enum Command {
     /// Enter the development environment
     Env(EnvArgs),
 
+    /// Delete store paths that are not used by any project
+    Gc,
+
     /// Create a new unnix manifest
     Init(InitArgs),
 
@@ -41,6 +44,12 @@ pub enum Command {
     /// Print information about the project
     Print(PrintArgs),
 
+    /// Serve the unnix store as a binary cache over HTTP
+    Serve(ServeArgs),
+
+    /// Manage the unnix store
+    Store(StoreArgs),
+
     /// Update the store paths in the lockfile
     Update,
 
@@ -115,6 +124,46 @@ pub struct PrintEnvArgs {
     pub system: SystemArgs,
 }
 
+#[derive(Parser)]
+pub struct ServeArgs {
+    /// Specify the address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:5000")]
+    pub listen: SocketAddr,
+
+    /// Specify the secret key to sign narinfos with, which is generated if it does not exist
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub key: Utf8PathBuf,
+
+    /// Specify the name of the key when generating one
+    #[arg(long, default_value = "unnix-1")]
+    pub key_name: String,
+}
+
+#[derive(Parser)]
+pub struct StoreArgs {
+    #[command(subcommand)]
+    pub command: StoreCommand,
+}
+
+#[derive(Subcommand)]
+pub enum StoreCommand {
+    /// Deduplicate identical files in the unnix store with hard links
+    Optimise,
+
+    /// Verify the closure of the environment against the store database
+    Verify(VerifyArgs),
+}
+
+#[derive(Parser)]
+pub struct VerifyArgs {
+    /// Download store paths that are missing, modified, or unregistered again
+    #[arg(long)]
+    pub repair: bool,
+
+    #[command(flatten)]
+    pub system: SystemArgs,
+}
+
 #[derive(Parser)]
 pub struct WithArgs {
     /// Specify the list of packages
@@ -135,6 +184,10 @@ pub struct WithArgs {
 
 #[derive(Parser)]
 pub struct GlobalArgs {
+    /// Specify the user config file, defaults to unnix/config.kdl in the config directory
+    #[arg(long, env = "UNNIX_CONFIG", global = true, value_hint = ValueHint::FilePath)]
+    pub config: Option<Utf8PathBuf>,
+
     /// Specify the directory the unnix manifest is in
     #[arg(short, long, global = true, value_hint = ValueHint::DirPath)]
     pub directory: Option<Utf8PathBuf>,
@@ -142,6 +195,14 @@ pub struct GlobalArgs {
     /// Assert the lockfile is up to date
     #[arg(long, global = true)]
     pub locked: bool,
+
+    /// Specify a netrc file with credentials for binary caches
+    #[arg(long, env = "UNNIX_NETRC_FILE", global = true, value_hint = ValueHint::FilePath)]
+    pub netrc_file: Option<Utf8PathBuf>,
+
+    /// Specify the unnix root, where the unnix store is kept
+    #[arg(long, env = "UNNIX_ROOT", global = true, value_hint = ValueHint::DirPath)]
+    pub root: Option<Utf8PathBuf>,
 }
 
 #[derive(Parser)]
@@ -0,0 +1,7 @@
+use miette::Result;
+
+use crate::{cli::GlobalArgs, state::standalone_root, store::Store};
+
+pub async fn gc(global: GlobalArgs) -> Result<()> {
+    Store::new(&standalone_root(global)?)?.gc().await
+}
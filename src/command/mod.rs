@@ -2,9 +2,12 @@ mod add;
 mod cache;
 mod ci;
 mod env;
+mod gc;
 mod init;
 mod lock;
 mod print;
+mod serve;
+mod store;
 mod update;
 mod with;
 
@@ -12,8 +15,11 @@ pub use add::add;
 pub use cache::cache;
 pub use ci::ci;
 pub use env::env;
+pub use gc::gc;
 pub use init::init;
 pub use lock::lock;
 pub use print::print;
+pub use serve::serve;
+pub use store::store;
 pub use update::update;
 pub use with::with;
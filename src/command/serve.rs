@@ -0,0 +1,53 @@
+use std::{
+    fs::{OpenOptions, read_to_string},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+};
+
+use harmonia_store_core::signature::SecretKey;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use ring::rand::SystemRandom;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{
+    cli::{GlobalArgs, ServeArgs},
+    serve::serve as run,
+    state::standalone_root,
+    store::Store,
+};
+
+pub async fn serve(global: GlobalArgs, args: ServeArgs) -> Result<()> {
+    let store = Store::new(&standalone_root(global)?)?;
+
+    let key: SecretKey = if args.key.exists() {
+        read_to_string(&args.key)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read {}", args.key))?
+            .trim()
+            .parse()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to parse {}", args.key))?
+    } else {
+        let key = SecretKey::generate(args.key_name, &SystemRandom::new()).into_diagnostic()?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&args.key)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to create {}", args.key))?;
+        writeln!(file, "{key}")
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to write {}", args.key))?;
+        info!("generated {}", args.key);
+        key
+    };
+
+    info!("public key: {}", key.to_public_key());
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to listen on {}", args.listen))?;
+    run(store, key, listener).await
+}
@@ -0,0 +1,23 @@
+use miette::Result;
+
+use crate::{
+    cli::{GlobalArgs, StoreArgs, StoreCommand, VerifyArgs},
+    state::{State, standalone_root},
+    store::Store,
+};
+
+pub async fn store(global: GlobalArgs, args: StoreArgs) -> Result<()> {
+    match args.command {
+        StoreCommand::Optimise => optimise(global).await,
+        StoreCommand::Verify(args) => verify(global, args).await,
+    }
+}
+
+async fn optimise(global: GlobalArgs) -> Result<()> {
+    Store::new(&standalone_root(global)?)?.optimise().await
+}
+
+async fn verify(global: GlobalArgs, args: VerifyArgs) -> Result<()> {
+    let state = State::new_locked(global, args.system.try_into()?).await?;
+    state.verify(args.repair).await
+}
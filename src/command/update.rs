@@ -15,14 +15,15 @@ pub async fn update(global: GlobalArgs) -> Result<()> {
     span.pb_start();
     let mut jobs = ResolverJobs::new(span);
 
-    for (system, manifest) in state.manifest.systems {
+    for (&system, manifest) in &state.manifest.systems {
         let lockfile = Rc::new(SystemLockfile::default());
-        for (name, pkg) in manifest.packages {
-            jobs.add(name, pkg.key()?, &pkg, system)?;
+        for (name, pkg) in &manifest.packages {
+            jobs.add(name.clone(), pkg.key()?, pkg, system)?;
         }
         state.lockfile.systems.insert(system, lockfile);
     }
 
     jobs.resolve(&state.lockfile).await?;
-    state.lockfile.write_dir(&state.dir)
+    state.lockfile.write_dir(&state.dir)?;
+    state.add_root().await
 }
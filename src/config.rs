@@ -0,0 +1,201 @@
+use std::{borrow::Cow, fs::read_to_string, io::ErrorKind, sync::OnceLock};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use dirs::config_dir;
+use kdl::KdlDocument;
+use miette::{Diagnostic, IntoDiagnostic, Report, Result, SourceSpan, WrapErr};
+use thiserror::Error;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// user-level settings that should not be part of the manifest,
+// since the manifest and lockfile are shared with everyone working on the project
+#[derive(Debug, Default)]
+struct Config {
+    rewrites: Vec<(String, String)>,
+}
+
+#[derive(Debug, Diagnostic, Error)]
+#[error("failed to parse config file")]
+struct ConfigError {
+    message: String,
+    #[source_code]
+    input: String,
+    #[label("{message}")]
+    span: SourceSpan,
+}
+
+// the config file is optional unless it is specified explicitly
+pub fn load(path: Option<&Utf8Path>) -> Result<()> {
+    let (path, text) = match path {
+        Some(path) => (
+            path.to_owned(),
+            read_to_string(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to read {path}"))?,
+        ),
+        None => {
+            let Some(dir) = config_dir() else {
+                return Ok(());
+            };
+            let Ok(path) = Utf8PathBuf::try_from(dir.join("unnix/config.kdl")) else {
+                return Ok(());
+            };
+            // the config file is optional, but it shouldn't be ignored if it can't be read
+            let text = match read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => {
+                    return Err(e)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("failed to read {path}"));
+                }
+            };
+            (path, text)
+        }
+    };
+
+    let config = parse(&text).wrap_err_with(|| format!("failed to load {path}"))?;
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+fn parse(text: &str) -> Result<Config> {
+    let doc: KdlDocument = text.parse()?;
+    let mut config = Config::default();
+
+    let err = |message: &str, span| {
+        Report::new(ConfigError {
+            message: message.into(),
+            input: text.into(),
+            span,
+        })
+    };
+
+    for node in doc.nodes() {
+        match node.name().value() {
+            // rewrite "https://cache.nixos.org" "https://nix-mirror.example.org"
+            "rewrite" => {
+                if let Some(children) = node.children() {
+                    return Err(err("unexpected children", children.span()));
+                }
+                let [from, to] = node.entries() else {
+                    return Err(err("expected two arguments", node.span()));
+                };
+                let mut args = [from, to].into_iter().map(|entry| {
+                    if entry.name().is_some() {
+                        return Err(err("unexpected property", entry.span()));
+                    }
+                    let Some(url) = entry.value().as_string() else {
+                        return Err(err("expected string", entry.span()));
+                    };
+                    Ok(url.trim_end_matches('/').to_owned())
+                });
+                let from = args.next().unwrap()?;
+                let to = args.next().unwrap()?;
+                config.rewrites.push((from, to));
+            }
+            _ => {
+                return Err(err("unknown node", node.name().span()));
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+// urls are rewritten with the longest matching prefix, like the url.<base>.insteadOf git setting,
+// except prefixes only match whole path segments
+pub fn rewrite(url: &str) -> Cow<'_, str> {
+    match CONFIG.get() {
+        Some(config) => apply(&config.rewrites, url),
+        None => Cow::Borrowed(url),
+    }
+}
+
+// maps a url on a mirror back to the url it was rewritten from,
+// so urls that were only found through a mirror, e.g. after a redirect, can be locked
+pub fn unrewrite(url: &str) -> Cow<'_, str> {
+    match CONFIG.get() {
+        Some(config) => revert(&config.rewrites, url),
+        None => Cow::Borrowed(url),
+    }
+}
+
+fn apply<'a>(rewrites: &[(String, String)], url: &'a str) -> Cow<'a, str> {
+    rewrites
+        .iter()
+        .filter_map(|(from, to)| {
+            let rest = url.strip_prefix(from.as_str())?;
+            matches!(rest.chars().next(), None | Some('/' | '?' | '#')).then_some((from, to, rest))
+        })
+        .max_by_key(|(from, ..)| from.len())
+        .map_or(Cow::Borrowed(url), |(_, to, rest)| {
+            Cow::Owned(format!("{to}{rest}"))
+        })
+}
+
+fn revert<'a>(rewrites: &[(String, String)], url: &'a str) -> Cow<'a, str> {
+    let reversed: Vec<_> = rewrites
+        .iter()
+        .map(|(from, to)| (to.clone(), from.clone()))
+        .collect();
+    apply(&reversed, url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, parse, revert};
+
+    #[test]
+    fn rewrite() {
+        let config = parse(
+            r#"
+rewrite "https://cache.nixos.org" "https://nix-mirror.example.org/cache/"
+rewrite "https://hydra.nixos.org" "https://nix-mirror.example.org/hydra"
+rewrite "https://hydra.nixos.org/job/nixpkgs" "https://nixpkgs-mirror.example.org"
+"#,
+        )
+        .unwrap();
+        let rewrite = |url| apply(&config.rewrites, url).into_owned();
+
+        assert_eq!(
+            rewrite("https://cache.nixos.org/"),
+            "https://nix-mirror.example.org/cache/",
+        );
+        assert_eq!(
+            rewrite("https://cache.nixos.org/nix-cache-info"),
+            "https://nix-mirror.example.org/cache/nix-cache-info",
+        );
+        assert_eq!(
+            rewrite("https://hydra.nixos.org"),
+            "https://nix-mirror.example.org/hydra",
+        );
+        assert_eq!(
+            rewrite("https://hydra.nixos.org/job/nixpkgs/unstable/hello.x86_64-linux"),
+            "https://nixpkgs-mirror.example.org/unstable/hello.x86_64-linux",
+        );
+        assert_eq!(
+            rewrite("https://cache.nixos.org.example.org/"),
+            "https://cache.nixos.org.example.org/",
+        );
+
+        let unrewrite = |url| revert(&config.rewrites, url).into_owned();
+        assert_eq!(
+            unrewrite("https://nix-mirror.example.org/cache/nix-cache-info"),
+            "https://cache.nixos.org/nix-cache-info",
+        );
+        assert_eq!(
+            unrewrite("https://nixpkgs-mirror.example.org/unstable/hello.x86_64-linux"),
+            "https://hydra.nixos.org/job/nixpkgs/unstable/hello.x86_64-linux",
+        );
+        assert_eq!(
+            unrewrite("https://releases.nixos.org/nixos/25.05/nixos-25.05.802216.55d1f923c480"),
+            "https://releases.nixos.org/nixos/25.05/nixos-25.05.802216.55d1f923c480",
+        );
+
+        assert!(parse(r#"rewrite "https://cache.nixos.org""#).is_err());
+        assert!(parse(r#"rewrite "https://cache.nixos.org" to="https://example.org""#).is_err());
+        assert!(parse("mirror").is_err());
+    }
+}
@@ -41,6 +41,20 @@ pub struct PackageLock {
     #[serde_as(as = "DisplayFromStr")]
     pub key: Base64Hash,
     pub outputs: BTreeMap<Rc<str>, StorePath>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
+// the snapshot of nixpkgs the outputs were resolved from, for resolvers that have one
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<Rc<str>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Rc<str>>,
 }
 
 impl Lockfile {
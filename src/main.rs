@@ -1,9 +1,12 @@
+mod cache;
 mod cli;
 mod command;
+mod config;
 mod lockfile;
 mod manifest;
 mod package;
 mod resolver;
+mod serve;
 mod state;
 mod store;
 mod system;
@@ -60,6 +63,9 @@ async fn main() -> Result<()> {
         Command::Env(env_args) => {
             command::env(args.global, env_args).await?;
         }
+        Command::Gc => {
+            command::gc(args.global).await?;
+        }
         Command::Init(init_args) => {
             command::init(args.global, init_args).await?;
         }
@@ -69,6 +75,12 @@ async fn main() -> Result<()> {
         Command::Print(print_args) => {
             command::print(args.global, print_args).await?;
         }
+        Command::Serve(serve_args) => {
+            command::serve(args.global, serve_args).await?;
+        }
+        Command::Store(store_args) => {
+            command::store(args.global, store_args).await?;
+        }
         Command::Update => {
             command::update(args.global).await?;
         }
@@ -1,6 +1,7 @@
 mod tests;
 
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
     fs::read_to_string,
     rc::Rc,
@@ -8,42 +9,71 @@ use std::{
     sync::Arc,
 };
 
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use harmonia_store_core::signature::PublicKey;
 use kdl::{KdlDocument, KdlNode};
 use miette::{Diagnostic, IntoDiagnostic, Report, Result, SourceSpan, WrapErr, miette};
 use thiserror::Error;
+use tokio::sync::OnceCell;
 use url::Url;
 
 use crate::{
+    cache::{self, Cache},
+    config,
     package::Package,
-    resolver::{Resolver, devbox::DevboxResolver, hydra::HydraResolver},
+    resolver::{
+        Resolver,
+        channel::ChannelResolver,
+        devbox::{DEVBOX_BASE, DevboxResolver},
+        hydra::HydraResolver,
+        r#static::StaticResolver,
+    },
+    store::path::StorePath,
     system::{Arch, Kernel, System},
 };
 
 #[derive(Debug)]
 pub struct Manifest {
     pub systems: BTreeMap<System, SystemManifest>,
+    pub store: StoreManifest,
+}
+
+#[derive(Debug, Default)]
+pub struct StoreManifest {
+    pub auto_optimise: bool,
+    pub root: Option<Utf8PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct SystemManifest {
     pub packages: BTreeMap<Rc<str>, Rc<Package>>,
     pub env: BTreeMap<Rc<str>, Rc<str>>,
-    pub caches: Vec<Arc<Url>>,
-    pub public_keys: Vec<Arc<PublicKey>>,
+    pub caches: Vec<Arc<Cache>>,
 }
 
 #[derive(Clone)]
 struct SurfaceSystemManifest<'a> {
     packages: BTreeMap<Rc<str>, SurfacePackage<'a>>,
     env: BTreeMap<Rc<str>, Rc<str>>,
-    caches: Vec<Arc<Url>>,
+    caches: Vec<SurfaceCache>,
     public_keys: Vec<Arc<PublicKey>>,
     default_cache: Option<bool>,
     resolvers: BTreeMap<&'a str, Rc<Resolver>>,
 }
 
+// caches without their own public keys trust the global ones
+#[derive(Clone)]
+struct SurfaceCache {
+    url: Url,
+    public_keys: Option<Vec<Arc<PublicKey>>>,
+    threshold: usize,
+    // reported if an explicit threshold is greater than the number of global keys
+    threshold_error: Option<ManifestError>,
+    priority: Option<u32>,
+    want_mass_query: Option<bool>,
+    token_env: Option<String>,
+}
+
 #[derive(Clone)]
 struct SurfacePackage<'a> {
     resolver: &'a str,
@@ -56,7 +86,7 @@ struct SystemPredicate {
     pub kernel: Option<Kernel>,
 }
 
-#[derive(Debug, Diagnostic, Error)]
+#[derive(Clone, Debug, Diagnostic, Error)]
 #[error("failed to parse manifest file")]
 struct ManifestError {
     message: String,
@@ -164,6 +194,7 @@ impl Manifest {
         let doc = text.parse()?;
         let mut systems = Vec::new();
         let mut manifests = Vec::new();
+        let mut store = StoreManifest::default();
 
         let default = SurfaceSystemManifest::from_document(text, &doc, |node| {
             kdl_macros!(text);
@@ -219,6 +250,33 @@ impl Manifest {
                     Ok(true)
                 }
 
+                "store" => {
+                    assert_no_entries!(node);
+
+                    for child in node.iter_children() {
+                        assert_no_children!(child);
+
+                        let name = child.name();
+                        match name.value() {
+                            "auto-optimise" => {
+                                let entry = arg!(child);
+                                store.auto_optimise = entry
+                                    .value()
+                                    .as_bool()
+                                    .wrap_err_with(|| err!(entry, "expected boolean"))?;
+                            }
+                            "root" => {
+                                store.root = Some(str_arg!(child).into());
+                            }
+                            _ => {
+                                bail!(child, "invalid field");
+                            }
+                        }
+                    }
+
+                    Ok(true)
+                }
+
                 _ => Ok(false),
             }
         })?;
@@ -272,6 +330,9 @@ impl Manifest {
                     manifest.default_cache = Some(new);
                 }
                 manifest.caches.extend(layer.caches.iter().cloned());
+                manifest
+                    .public_keys
+                    .extend(layer.public_keys.iter().cloned());
 
                 manifest.resolvers.extend(
                     layer
@@ -282,7 +343,7 @@ impl Manifest {
             }
         }
 
-        let cache = Arc::new(Url::parse("https://cache.nixos.org").into_diagnostic()?);
+        let url = Url::parse("https://cache.nixos.org").into_diagnostic()?;
         let pk = Arc::new(DEFAULT_PUBLIC_KEY.parse::<PublicKey>().into_diagnostic()?);
         let systems = systems
             .into_iter()
@@ -296,23 +357,64 @@ impl Manifest {
                     })
                     .collect::<Result<_>>()?;
 
+                let mut caches = Vec::new();
                 if manifest.default_cache.unwrap_or(true) {
-                    manifest.caches.insert(0, cache.clone());
+                    caches.push(Arc::new(Cache {
+                        url: rewrite_url(&url)?,
+                        public_keys: vec![pk.clone()],
+                        threshold: 1,
+                        priority: None,
+                        want_mass_query: None,
+                        token_env: None,
+                        info: OnceCell::new(),
+                    }));
                     manifest.public_keys.insert(0, pk.clone());
                 }
+                for cache in manifest.caches {
+                    if let Some(e) = cache.threshold_error
+                        && cache.public_keys.is_none()
+                        && cache.threshold > manifest.public_keys.len()
+                    {
+                        return Err(Report::new(e));
+                    }
+                    caches.push(Arc::new(Cache {
+                        url: rewrite_url(&cache.url)?,
+                        public_keys: cache
+                            .public_keys
+                            .unwrap_or_else(|| manifest.public_keys.clone()),
+                        threshold: cache.threshold,
+                        priority: cache.priority,
+                        want_mass_query: cache.want_mass_query,
+                        token_env: cache.token_env,
+                        info: OnceCell::new(),
+                    }));
+                }
 
                 let manifest = SystemManifest {
                     packages,
                     env: manifest.env,
-                    caches: manifest.caches,
-                    public_keys: manifest.public_keys,
+                    caches,
                 };
 
                 Ok((system, manifest))
             })
             .collect::<Result<_>>()?;
 
-        Ok(Manifest { systems })
+        Ok(Manifest { systems, store })
+    }
+
+    // relative roots are relative to the directory of the manifest
+    pub fn root(&self, dir: &Utf8Path) -> Option<Utf8PathBuf> {
+        self.store.root.as_ref().map(|root| dir.join(root))
+    }
+}
+
+// mirrors from the user config are only applied in memory, so the manifest stays portable
+fn rewrite_url(url: &Url) -> Result<Url> {
+    match config::rewrite(url.as_str()) {
+        Cow::Borrowed(_) => Ok(url.clone()),
+        Cow::Owned(rewritten) => cache::parse_url(&rewritten)
+            .map_err(|e| miette!("failed to rewrite {url} to {rewritten}: {e}")),
     }
 }
 
@@ -419,41 +521,141 @@ impl<'a> SurfaceSystemManifest<'a> {
                         let name = child.name();
                         if name.value() == "public-keys" {
                             assert_no_entries!(child);
+                            public_keys.extend(parse_public_keys(text, child)?);
+                            continue;
+                        }
 
-                            for child in child.iter_children() {
-                                assert_no_entries!(child);
-                                assert_no_children!(child);
+                        let url = match cache::parse_url(name.value()) {
+                            Ok(url) => url,
+                            Err(e) => {
+                                bail!(name, "{e}");
+                            }
+                        };
 
-                                let name = child.name();
-                                match name.value().parse() {
-                                    Ok(pk) => {
-                                        public_keys.push(Arc::new(pk));
+                        let mut threshold = 1;
+                        let mut threshold_entry = None;
+                        let mut priority = None;
+                        let mut want_mass_query = None;
+                        let mut token_env = None;
+                        for entry in child.entries() {
+                            if let Some(name) = entry.name() {
+                                match name.value() {
+                                    "threshold" => {
+                                        threshold = entry
+                                            .value()
+                                            .as_integer()
+                                            .and_then(|n| usize::try_from(n).ok())
+                                            .filter(|&n| n > 0)
+                                            .wrap_err_with(|| {
+                                                err!(entry, "expected positive integer")
+                                            })?;
+                                        threshold_entry = Some(entry);
+                                    }
+                                    "priority" => {
+                                        priority = Some(
+                                            entry
+                                                .value()
+                                                .as_integer()
+                                                .and_then(|n| u32::try_from(n).ok())
+                                                .wrap_err_with(|| {
+                                                    err!(entry, "expected non-negative integer")
+                                                })?,
+                                        );
+                                    }
+                                    "want-mass-query" => {
+                                        want_mass_query =
+                                            Some(entry.value().as_bool().wrap_err_with(|| {
+                                                err!(entry, "expected boolean")
+                                            })?);
+                                    }
+                                    "token-env" => {
+                                        token_env = Some(str!(entry).to_owned());
                                     }
-                                    Err(e) => {
-                                        bail!(name, "{e}");
+                                    _ => {
+                                        bail!(name, "invalid property");
                                     }
                                 }
+                            } else {
+                                bail!(entry, "unexpected argument");
                             }
+                        }
 
-                            continue;
+                        let mut scoped_keys = None;
+                        for child in child.iter_children() {
+                            if child.name().value() == "public-keys" {
+                                assert_no_entries!(child);
+                                scoped_keys
+                                    .get_or_insert_with(Vec::new)
+                                    .extend(parse_public_keys(text, child)?);
+                            } else {
+                                bail!(child, "invalid field");
+                            }
                         }
 
-                        assert_no_entries!(child);
+                        // a threshold that can never be met would reject every narinfo
+                        let threshold_error = threshold_entry.map(|entry| {
+                            err!(entry, "threshold is greater than the number of public keys")
+                        });
+                        if let Some(keys) = &scoped_keys
+                            && threshold > keys.len()
+                            && let Some(e) = &threshold_error
+                        {
+                            return Err(Report::new(e.clone()));
+                        }
+
+                        caches.push(SurfaceCache {
+                            url,
+                            public_keys: scoped_keys,
+                            threshold,
+                            threshold_error,
+                            priority,
+                            want_mass_query,
+                            token_env,
+                        });
+                    }
+                }
+
+                "channel" => {
+                    let name = str_arg!(node);
+                    let mut base = "https://channels.nixos.org";
+                    let mut channel = None;
+                    let mut package = "{package}";
+
+                    for child in node.iter_children() {
                         assert_no_children!(child);
 
-                        match name.value().parse() {
-                            Ok(cache) => {
-                                caches.push(Arc::new(cache));
+                        let name = child.name();
+                        match name.value() {
+                            "base" => {
+                                base = str_arg!(child);
                             }
-                            Err(e) => {
-                                bail!(name, "{e}");
+                            "channel" => {
+                                channel = Some(str_arg!(child));
+                            }
+                            "package" => {
+                                package = str_arg!(child);
+                            }
+                            _ => {
+                                bail!(child, "invalid field");
                             }
                         }
                     }
+
+                    let resolver = Resolver::Channel(ChannelResolver {
+                        base: base.trim_end_matches('/').into(),
+                        channel: channel
+                            .wrap_err_with(|| err!(node, "missing channel"))?
+                            .into(),
+                        package: package.into(),
+                    });
+                    if resolvers.insert(name, resolver.into()).is_some() {
+                        bail!(node, "duplicate resolver");
+                    }
                 }
 
                 "devbox" => {
                     let name = str_arg!(node);
+                    let mut base = DEVBOX_BASE;
                     let mut package = "{package}";
 
                     for child in node.iter_children() {
@@ -461,6 +663,9 @@ impl<'a> SurfaceSystemManifest<'a> {
 
                         let name = child.name();
                         match name.value() {
+                            "base" => {
+                                base = str_arg!(child);
+                            }
                             "package" => {
                                 package = str_arg!(child);
                             }
@@ -471,6 +676,7 @@ impl<'a> SurfaceSystemManifest<'a> {
                     }
 
                     let resolver = Resolver::Devbox(DevboxResolver {
+                        base: base.trim_end_matches('/').into(),
                         package: package.into(),
                     });
                     if resolvers.insert(name, resolver.into()).is_some() {
@@ -484,6 +690,8 @@ impl<'a> SurfaceSystemManifest<'a> {
                     let mut project = None;
                     let mut jobset = None;
                     let mut job = "{package}.{system}";
+                    let mut eval = None;
+                    let mut revision = None;
 
                     for child in node.iter_children() {
                         assert_no_children!(child);
@@ -502,12 +710,36 @@ impl<'a> SurfaceSystemManifest<'a> {
                             "job" => {
                                 job = str_arg!(child);
                             }
+                            "eval" => {
+                                let arg = arg!(child);
+                                eval = Some(
+                                    arg.value()
+                                        .as_integer()
+                                        .and_then(|n| u64::try_from(n).ok())
+                                        .wrap_err_with(|| {
+                                            err!(arg, "expected non-negative integer")
+                                        })?,
+                                );
+                            }
+                            "revision" => {
+                                let arg = arg!(child);
+                                let value = str!(arg);
+                                if value.len() < 7 || !value.bytes().all(|b| b.is_ascii_hexdigit())
+                                {
+                                    bail!(arg, "expected a git revision");
+                                }
+                                revision = Some(value.to_ascii_lowercase().into());
+                            }
                             _ => {
                                 bail!(child, "invalid field");
                             }
                         }
                     }
 
+                    if eval.is_some() && revision.is_some() {
+                        bail!(node, "eval and revision cannot be used together");
+                    }
+
                     let resolver = Resolver::Hydra(HydraResolver {
                         base: base.wrap_err_with(|| err!(node, "missing base"))?.into(),
                         project: project
@@ -517,12 +749,49 @@ impl<'a> SurfaceSystemManifest<'a> {
                             .wrap_err_with(|| err!(node, "missing jobset"))?
                             .into(),
                         job: job.into(),
+                        eval,
+                        revision,
                     });
                     if resolvers.insert(name, resolver.into()).is_some() {
                         bail!(node, "duplicate resolver");
                     }
                 }
 
+                "static" => {
+                    let name = str_arg!(node);
+                    let mut packages = BTreeMap::new();
+
+                    for child in node.iter_children() {
+                        assert_no_entries!(child);
+
+                        let mut outputs = BTreeMap::new();
+                        for output in child.iter_children() {
+                            assert_no_children!(output);
+
+                            let arg = arg!(output);
+                            let path = StorePath::new(str!(arg)).map_err(|e| err!(arg, "{e}"))?;
+                            if outputs.insert(output.name().value().into(), path).is_some() {
+                                bail!(output, "duplicate output");
+                            }
+                        }
+
+                        if outputs.is_empty() {
+                            bail!(child, "expected at least one output");
+                        }
+                        if packages
+                            .insert(child.name().value().into(), outputs)
+                            .is_some()
+                        {
+                            bail!(child, "duplicate package");
+                        }
+                    }
+
+                    let resolver = Resolver::Static(StaticResolver { packages });
+                    if resolvers.insert(name, resolver.into()).is_some() {
+                        bail!(node, "duplicate resolver");
+                    }
+                }
+
                 _ => {
                     if !handle_unknown(node)? {
                         bail!(name, "invalid node");
@@ -542,6 +811,25 @@ impl<'a> SurfaceSystemManifest<'a> {
     }
 }
 
+fn parse_public_keys(text: &str, node: &KdlNode) -> Result<Vec<Arc<PublicKey>>> {
+    kdl_macros!(text);
+
+    node.iter_children()
+        .map(|child| {
+            assert_no_entries!(child);
+            assert_no_children!(child);
+
+            let name = child.name();
+            match name.value().parse() {
+                Ok(pk) => Ok(Arc::new(pk)),
+                Err(e) => {
+                    bail!(name, "{e}");
+                }
+            }
+        })
+        .collect()
+}
+
 impl Package {
     fn from_surface(
         pkg: SurfacePackage,
@@ -15,12 +15,71 @@ fn basic() {
     assert_debug_snapshot!(manifest!("basic.kdl"));
 }
 
+#[test]
+fn cache_keys() {
+    assert_debug_snapshot!(manifest!("cache-keys.kdl"));
+}
+
 #[test]
 fn cache_no_default() {
     assert_debug_snapshot!(manifest!("cache-no-default.kdl"));
 }
 
+#[test]
+fn cache_threshold() {
+    for text in [
+        r#"caches { "https://cache.example.org" threshold=0; }"#,
+        r#"caches { "https://cache.example.org" threshold=-1; }"#,
+        r#"caches { "https://cache.example.org" threshold=2; }"#,
+        r#"caches default=#false { "https://cache.example.org" threshold=1; }"#,
+        r#"
+caches {
+  "https://cache.example.org" threshold=2 {
+    public-keys {
+      "a.example.org-1:mB9FSh9qf2dCimDSUo8Zy7bkq5CX+/rkCWyvRCYg3Fs="
+    }
+  }
+}
+"#,
+    ] {
+        assert!(Manifest::parse(text).is_err(), "{text}");
+    }
+
+    // the default key of cache.nixos.org counts towards the global keys
+    Manifest::parse(
+        r#"
+caches {
+  "https://cache.example.org" threshold=2
+  public-keys {
+    "a.example.org-1:mB9FSh9qf2dCimDSUo8Zy7bkq5CX+/rkCWyvRCYg3Fs="
+  }
+}
+"#,
+    )
+    .unwrap();
+}
+
+#[test]
+fn channel() {
+    assert_debug_snapshot!(manifest!("channel.kdl"));
+}
+
+#[test]
+fn devbox() {
+    assert_debug_snapshot!(manifest!("devbox.kdl"));
+}
+
 #[test]
 fn hydra() {
     assert_debug_snapshot!(manifest!("hydra.kdl"));
 }
+
+#[test]
+fn store() {
+    assert_debug_snapshot!(manifest!("store.kdl"));
+}
+
+#[test]
+fn r#static() {
+    assert_debug_snapshot!(manifest!("static.kdl"));
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use async_compression::tokio::bufread::XzDecoder;
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use serde::Serialize;
+use tokio::{
+    io::AsyncReadExt,
+    task::{JoinSet, LocalSet},
+};
+use tracing::{Span, debug};
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use crate::{
+    config,
+    lockfile::{Lockfile, PackageLock, Source},
+    package::Base64Hash,
+    resolver::format,
+    state::HTTP_CLIENT,
+    store::path::StorePath,
+    system::System,
+};
+
+// suffixes of store path names that are recognized as outputs instead of being part of the version
+const OUTPUTS: &[&str] = &[
+    "bin", "data", "debug", "dev", "devdoc", "doc", "info", "lib", "man", "modules", "out",
+    "python", "static", "terminfo",
+];
+
+#[derive(Default)]
+pub struct ChannelJobs {
+    jobs: BTreeMap<Channel, Vec<ChannelPackage>>,
+    // the releases that already locked packages were resolved from
+    locked: BTreeMap<Channel, Rc<str>>,
+    // the listing has no system information, so each channel can only serve one system
+    systems: BTreeMap<Channel, System>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelResolver {
+    pub base: Rc<str>,
+    pub channel: Rc<str>,
+    pub package: String,
+}
+
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
+struct Channel {
+    base: Rc<str>,
+    channel: Rc<str>,
+}
+
+struct ChannelPackage {
+    name: Rc<str>,
+    key: Base64Hash,
+    package: String,
+    system: System,
+    outputs: Rc<BTreeSet<String>>,
+}
+
+// store paths in the channel by pname, like the one from builtins.parseDrvName
+#[derive(Default)]
+struct Index {
+    pnames: HashMap<String, Vec<Entry>>,
+}
+
+struct Entry {
+    version: String,
+    output: String,
+    path: StorePath,
+}
+
+impl ChannelJobs {
+    pub async fn resolve(self, span: &Span, lockfile: &Lockfile) -> Result<()> {
+        let local = LocalSet::new();
+        let mut tasks = JoinSet::new();
+
+        for (channel, pkgs) in self.jobs {
+            let locked = self.locked.get(&channel).cloned();
+            let lockfiles = lockfile.systems.clone();
+            let span = span.clone();
+            tasks.spawn_local_on(
+                async move {
+                    let (release, index) = channel.fetch(locked.as_deref()).await?;
+                    let source = Source {
+                        eval: None,
+                        revision: revision(&release),
+                        channel: Some(release.as_str().into()),
+                    };
+                    for pkg in pkgs {
+                        let outputs =
+                            index.find(&pkg.package, &pkg.outputs).wrap_err_with(|| {
+                                if locked.is_some() {
+                                    format!(
+                                        "failed to resolve {} from {release}, which the other \
+                                         packages are locked to, run `unnix update` to resolve \
+                                         every package from a newer release",
+                                        pkg.package,
+                                    )
+                                } else {
+                                    format!("failed to resolve {} from {release}", pkg.package)
+                                }
+                            })?;
+                        lockfiles[&pkg.system].inner.insert(
+                            pkg.name,
+                            PackageLock {
+                                key: pkg.key,
+                                outputs,
+                                source: Some(source.clone()),
+                            },
+                        );
+                        span.pb_inc(1);
+                    }
+                    Result::<_>::Ok(())
+                },
+                &local,
+            );
+        }
+
+        local
+            .run_until(async {
+                while let Some(res) = tasks.join_next().await {
+                    res.into_diagnostic()??;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    pub fn keep(
+        &mut self,
+        channel: &ChannelResolver,
+        system: System,
+        release: &Rc<str>,
+    ) -> Result<()> {
+        let channel = Channel::new(channel);
+        self.check_system(&channel, system)?;
+        self.locked
+            .entry(channel)
+            .or_insert_with(|| release.clone());
+        Ok(())
+    }
+
+    pub fn add(
+        &mut self,
+        channel: &ChannelResolver,
+        name: Rc<str>,
+        key: Base64Hash,
+        package: &str,
+        system: System,
+        outputs: Rc<BTreeSet<String>>,
+    ) -> Result<()> {
+        let pkg = ChannelPackage::new(channel, name, key, package, system, outputs)?;
+        let channel = Channel::new(channel);
+        self.check_system(&channel, system)?;
+        self.jobs.entry(channel).or_default().push(pkg);
+        Ok(())
+    }
+
+    fn check_system(&mut self, channel: &Channel, system: System) -> Result<()> {
+        let other = *self.systems.entry(channel.clone()).or_insert(system);
+        if other != system {
+            bail!(
+                help = "use a separate channel resolver in a system node for each system",
+                "channel {} cannot be used for both {other} and {system}, \
+                 since its listing does not distinguish systems",
+                channel.channel,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Channel {
+    fn new(channel: &ChannelResolver) -> Self {
+        Self {
+            base: channel.base.clone(),
+            channel: channel.channel.clone(),
+        }
+    }
+
+    // channels redirect to their latest release, whose url is locked so it can be fetched again,
+    // without the rewrite so a mirror does not end up in the lockfile
+    async fn fetch(&self, locked: Option<&str>) -> Result<(String, Index)> {
+        let url = match locked {
+            Some(release) => format!("{}/store-paths.xz", config::rewrite(release)),
+            None => format!(
+                "{}/{}/store-paths.xz",
+                config::rewrite(&self.base),
+                self.channel,
+            ),
+        };
+        debug!(url);
+
+        let res = HTTP_CLIENT
+            .get(&url)
+            .send()
+            .await
+            .into_diagnostic()?
+            .error_for_status()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to fetch {url}"))?;
+        let release = match locked {
+            Some(release) => release.to_owned(),
+            None => {
+                let mut release = res.url().clone();
+                release
+                    .path_segments_mut()
+                    .map_err(|()| miette!("failed to find the release of {}", self.channel))?
+                    .pop();
+                config::unrewrite(release.as_str()).into_owned()
+            }
+        };
+        let bytes = res.bytes().await.into_diagnostic()?;
+
+        let mut listing = String::new();
+        XzDecoder::new(&*bytes)
+            .read_to_string(&mut listing)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to decompress {url}"))?;
+
+        Ok((release, Index::new(&listing)?))
+    }
+}
+
+impl ChannelPackage {
+    fn new(
+        channel: &ChannelResolver,
+        name: Rc<str>,
+        key: Base64Hash,
+        package: &str,
+        system: System,
+        outputs: Rc<BTreeSet<String>>,
+    ) -> Result<Self> {
+        Ok(Self {
+            name,
+            key,
+            package: format(&channel.package, package, system)?,
+            system,
+            outputs,
+        })
+    }
+}
+
+impl Index {
+    fn new(listing: &str) -> Result<Self> {
+        let mut index = Self::default();
+        for line in listing.lines().filter(|line| !line.is_empty()) {
+            let path = StorePath::new(line)?;
+            let (pname, version) = parse_drv_name(path.name());
+            let (version, output) = match version.rsplit_once('-') {
+                Some((version, output)) if OUTPUTS.contains(&output) => (version, output),
+                _ => (version, "out"),
+            };
+            index.pnames.entry(pname.into()).or_default().push(Entry {
+                version: version.into(),
+                output: output.into(),
+                path,
+            });
+        }
+        Ok(index)
+    }
+
+    // packages can be pinned to a version with the `@<version>` syntax,
+    // which is required if the channel has multiple versions of the package
+    fn find(
+        &self,
+        package: &str,
+        wanted: &BTreeSet<String>,
+    ) -> Result<BTreeMap<Rc<str>, StorePath>> {
+        let (pname, version) = match package.rsplit_once('@') {
+            Some((pname, version)) if !version.is_empty() => (pname, Some(version)),
+            _ => (package, None),
+        };
+        let Some(entries) = self.pnames.get(pname) else {
+            bail!("{pname} was not found in the channel");
+        };
+
+        let versions: Vec<_> = entries
+            .iter()
+            .map(|entry| &*entry.version)
+            .sorted()
+            .dedup()
+            .collect();
+        let version = match version {
+            Some(version) => {
+                if !versions.contains(&version) {
+                    bail!(
+                        "{pname}@{version} was not found in the channel, available versions: {}",
+                        versions.join(", "),
+                    );
+                }
+                version
+            }
+            None => match versions[..] {
+                [version] => version,
+                _ => bail!(
+                    help = "specify a version with {pname}@<version>",
+                    "multiple versions of {pname} were found in the channel: {}",
+                    versions.join(", "),
+                ),
+            },
+        };
+
+        let mut outputs = BTreeMap::new();
+        for entry in entries.iter().filter(|entry| entry.version == version) {
+            if !wanted.is_empty() && !wanted.contains(&entry.output) {
+                continue;
+            }
+            if let Some(path) = outputs.insert(entry.output.as_str().into(), entry.path.clone())
+                && path != entry.path
+            {
+                bail!(
+                    "multiple store paths were found for {pname}@{version}: {path} and {}",
+                    entry.path,
+                );
+            }
+        }
+
+        if let Some(output) = wanted
+            .iter()
+            .find(|output| !outputs.contains_key(output.as_str()))
+        {
+            bail!("output {output} of {pname}@{version} was not found in the channel");
+        }
+        Ok(outputs)
+    }
+}
+
+// releases are named <version>.<number>.<revision> or <version>pre<number>.<revision>,
+// e.g. nixos-25.11.1234.0123456789ab or nixpkgs-26.05pre123456.0123456789ab,
+// while channels that don't redirect to a release only have the name of the channel
+fn revision(release: &str) -> Option<Rc<str>> {
+    let name = release.rsplit('/').next()?;
+    let (rest, revision) = name.rsplit_once('.')?;
+    let prefix = rest.trim_end_matches(|c: char| c.is_ascii_digit());
+    (prefix.len() < rest.len()
+        && (prefix.ends_with('.') || prefix.ends_with("pre"))
+        && revision.len() >= 7
+        && revision.bytes().all(|b| b.is_ascii_hexdigit()))
+    .then(|| revision.into())
+}
+
+// the version starts at the first dash that is not followed by a letter
+fn parse_drv_name(name: &str) -> (&str, &str) {
+    name.char_indices()
+        .find(|&(i, c)| {
+            c == '-'
+                && !name[i + 1 ..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic())
+        })
+        .map_or((name, ""), |(i, _)| (&name[.. i], &name[i + 1 ..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{Index, parse_drv_name, revision};
+
+    #[test]
+    fn drv_name() {
+        assert_eq!(parse_drv_name("hello-2.12.2"), ("hello", "2.12.2"));
+        assert_eq!(
+            parse_drv_name("pkg-config-unwrapped-0.29.2-man"),
+            ("pkg-config-unwrapped", "0.29.2-man"),
+        );
+        assert_eq!(
+            parse_drv_name("python3.13-numpy-2.3.4"),
+            ("python3.13-numpy", "2.3.4"),
+        );
+        assert_eq!(parse_drv_name("source"), ("source", ""));
+    }
+
+    #[test]
+    fn release_revision() {
+        assert_eq!(
+            revision("https://releases.nixos.org/nixos/25.11/nixos-25.11.1234.0123456789ab")
+                .as_deref(),
+            Some("0123456789ab"),
+        );
+        assert_eq!(
+            revision("https://releases.nixos.org/nixpkgs/nixpkgs-26.05pre123456.0123456789ab",)
+                .as_deref(),
+            Some("0123456789ab"),
+        );
+        assert_eq!(revision("https://nix-mirror.example.org/nixos-25.11"), None);
+        assert_eq!(
+            revision("https://nix-mirror.example.org/nixos-unstable"),
+            None
+        );
+    }
+
+    #[test]
+    fn find() {
+        let index = Index::new(
+            "/nix/store/hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1
+/nix/store/5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66
+/nix/store/p2f715285hm39x2jkkh5bwmpfz2x2wkr-clang-21.1.8-lib
+/nix/store/kk7ks8kmdd9n50swx8iv4mdsdw8lf718-clang-21.1.8
+/nix/store/acg8dgva4y915w5dhfyxrsxrmhsz11a7-clang-19.1.7
+",
+        )
+        .unwrap();
+        let outputs = |package, wanted: &[&str]| {
+            index
+                .find(
+                    package,
+                    &wanted.iter().map(|&s| s.into()).collect::<BTreeSet<_>>(),
+                )
+                .map(|outputs| {
+                    outputs
+                        .into_iter()
+                        .map(|(output, path)| format!("{output}={path}"))
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(
+            outputs("hello", &[]).unwrap(),
+            ["out=hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1"],
+        );
+        assert_eq!(
+            outputs("glibc", &[]).unwrap(),
+            ["out=5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66"],
+        );
+        assert_eq!(
+            outputs("clang@21.1.8", &[]).unwrap(),
+            [
+                "lib=p2f715285hm39x2jkkh5bwmpfz2x2wkr-clang-21.1.8-lib",
+                "out=kk7ks8kmdd9n50swx8iv4mdsdw8lf718-clang-21.1.8",
+            ],
+        );
+        assert_eq!(
+            outputs("clang@21.1.8", &["lib"]).unwrap(),
+            ["lib=p2f715285hm39x2jkkh5bwmpfz2x2wkr-clang-21.1.8-lib"],
+        );
+        assert!(outputs("clang", &[]).is_err());
+        assert!(outputs("clang@19.1.7", &["lib"]).is_err());
+        assert!(outputs("missing", &[]).is_err());
+    }
+}
@@ -11,6 +11,7 @@ use tracing_indicatif::span_ext::IndicatifSpanExt;
 use url::Url;
 
 use crate::{
+    config,
     lockfile::{Lockfile, PackageLock},
     package::Base64Hash,
     resolver::format,
@@ -24,13 +25,19 @@ pub struct DevboxJobs {
     jobs: BTreeMap<DevboxPackage, BTreeMap<System, Vec<DevboxPackageLock>>>,
 }
 
+pub const DEVBOX_BASE: &str = "https://search.devbox.sh";
+
 #[derive(Debug, Serialize)]
 pub struct DevboxResolver {
+    // skipped when unchanged, so the keys of existing lockfiles stay the same
+    #[serde(skip_serializing_if = "is_default_base")]
+    pub base: Rc<str>,
     pub package: String,
 }
 
 #[derive(Eq, Ord, PartialEq, PartialOrd)]
 struct DevboxPackage {
+    base: Rc<str>,
     name: String,
     version: String,
 }
@@ -96,6 +103,7 @@ impl DevboxJobs {
                                 PackageLock {
                                     key: lock.key,
                                     outputs,
+                                    source: None,
                                 },
                             );
                         }
@@ -120,11 +128,13 @@ impl DevboxJobs {
             && !version.is_empty()
         {
             DevboxPackage {
+                base: devbox.base.clone(),
                 name: name.into(),
                 version: version.into(),
             }
         } else {
             DevboxPackage {
+                base: devbox.base.clone(),
                 name: pkg,
                 version: "latest".into(),
             }
@@ -141,13 +151,17 @@ impl DevboxJobs {
     }
 }
 
+fn is_default_base(base: &Rc<str>) -> bool {
+    &**base == DEVBOX_BASE
+}
+
 impl DevboxPackage {
     async fn resolve(
         &self,
         systems: BTreeSet<System>,
     ) -> Result<BTreeMap<System, BTreeMap<Rc<str>, StorePath>>> {
         let url = Url::parse_with_params(
-            "https://search.devbox.sh/v2/resolve",
+            &config::rewrite(&format!("{}/v2/resolve", self.base)),
             [("name", &self.name), ("version", &self.version)],
         )
         .into_diagnostic()?;
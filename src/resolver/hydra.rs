@@ -3,9 +3,10 @@ use std::{
     rc::Rc,
 };
 
-use miette::{IntoDiagnostic, Result, WrapErr, miette};
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
 use reqwest::{Method, header::ACCEPT};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use tokio::{
     sync::Semaphore,
     task::{JoinSet, LocalSet},
@@ -14,7 +15,8 @@ use tracing::{Span, debug};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 use crate::{
-    lockfile::{Lockfile, PackageLock},
+    config,
+    lockfile::{Lockfile, PackageLock, Source},
     package::Base64Hash,
     resolver::format,
     state::HTTP_CLIENT,
@@ -22,9 +24,18 @@ use crate::{
     system::System,
 };
 
+// how many of the latest evaluations are searched for one where every job succeeded
+const MAX_EVALS: usize = 20;
+
+// how many of the latest evaluations are searched for a revision,
+// which covers a few months of nixpkgs-unstable
+const MAX_REVISION_EVALS: usize = 500;
+
 #[derive(Default)]
 pub struct HydraJobs {
-    jobs: BTreeMap<Rc<str>, BTreeMap<System, Vec<HydraPackage>>>,
+    jobs: BTreeMap<Jobset, Vec<HydraPackage>>,
+    // the latest evaluations that already locked packages were resolved from
+    locked: BTreeMap<Jobset, u64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -33,21 +44,55 @@ pub struct HydraResolver {
     pub project: Rc<str>,
     pub jobset: Rc<str>,
     pub job: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<Rc<str>>,
+}
+
+// every package from the same jobset is resolved from the same evaluation,
+// so they all come from the same revision of nixpkgs
+#[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
+struct Jobset {
+    base: Rc<str>,
+    project: Rc<str>,
+    jobset: Rc<str>,
+    eval: Option<u64>,
+    revision: Option<Rc<str>>,
 }
 
 struct HydraPackage {
     name: Rc<str>,
     key: Base64Hash,
-    project: Rc<str>,
-    jobset: Rc<str>,
     job: String,
+    system: System,
     outputs: Rc<BTreeSet<String>>,
 }
 
+#[derive(Deserialize)]
+struct Evals {
+    evals: Vec<Eval>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Eval {
+    id: u64,
+    #[serde(default)]
+    jobsetevalinputs: BTreeMap<String, EvalInput>,
+    flake: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EvalInput {
+    revision: Option<Rc<str>>,
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum Build {
     Ok {
+        buildstatus: Option<u64>,
         buildoutputs: BTreeMap<Rc<str>, Output>,
     },
     Err {
@@ -60,40 +105,47 @@ struct Output {
     path: String,
 }
 
+type Outputs = BTreeMap<Rc<str>, StorePath>;
+
 impl HydraJobs {
     pub async fn resolve(self, span: &Span, lockfile: &Lockfile) -> Result<()> {
         let local = LocalSet::new();
         let mut tasks = JoinSet::new();
 
-        for (base, jobs) in self.jobs {
-            // allow at most 4 concurrent clients per hydra instance
-            let semaphore = Rc::new(Semaphore::new(4));
-
-            for (system, pkgs) in jobs {
-                let lockfile = &lockfile.systems[&system];
-                for pkg in pkgs {
-                    let base = base.clone();
-                    let lockfile = lockfile.clone();
-                    let semaphore = semaphore.clone();
-                    let span = span.clone();
-                    tasks.spawn_local_on(
-                        async move {
-                            let _permit = semaphore.acquire().await.into_diagnostic()?;
-                            let outputs = pkg.resolve(&base, system).await?;
-                            lockfile.inner.insert(
-                                pkg.name,
-                                PackageLock {
-                                    key: pkg.key,
-                                    outputs,
-                                },
-                            );
-                            span.pb_inc(1);
-                            Result::<_>::Ok(())
-                        },
-                        &local,
-                    );
-                }
-            }
+        // allow at most 4 concurrent clients per hydra instance
+        let mut semaphores = BTreeMap::new();
+
+        for (jobset, pkgs) in self.jobs {
+            let semaphore = semaphores
+                .entry(jobset.base.clone())
+                .or_insert_with(|| Rc::new(Semaphore::new(4)))
+                .clone();
+            let locked = self.locked.get(&jobset).copied();
+            let lockfiles = lockfile.systems.clone();
+            let span = span.clone();
+            tasks.spawn_local_on(
+                async move {
+                    let (eval, outputs) = jobset.resolve(&pkgs, locked, &semaphore).await?;
+                    let source = Source {
+                        eval: Some(eval.id),
+                        revision: eval.revision(),
+                        channel: None,
+                    };
+                    for (pkg, outputs) in pkgs.into_iter().zip(outputs) {
+                        lockfiles[&pkg.system].inner.insert(
+                            pkg.name,
+                            PackageLock {
+                                key: pkg.key,
+                                outputs,
+                                source: Some(source.clone()),
+                            },
+                        );
+                        span.pb_inc(1);
+                    }
+                    Result::<_>::Ok(())
+                },
+                &local,
+            );
         }
 
         local
@@ -106,6 +158,13 @@ impl HydraJobs {
             .await
     }
 
+    pub fn keep(&mut self, hydra: &HydraResolver, eval: u64) {
+        self.locked
+            .entry(Jobset::new(hydra))
+            .and_modify(|locked| *locked = eval.max(*locked))
+            .or_insert(eval);
+    }
+
     pub fn add(
         &mut self,
         hydra: &HydraResolver,
@@ -118,69 +177,298 @@ impl HydraJobs {
         let pkg = HydraPackage {
             name,
             key,
-            project: hydra.project.clone(),
-            jobset: hydra.jobset.clone(),
             job: format(&hydra.job, package, system)?,
+            system,
             outputs,
         };
 
-        self.jobs
-            .entry(hydra.base.clone())
-            .or_default()
-            .entry(system)
-            .or_default()
-            .push(pkg);
+        self.jobs.entry(Jobset::new(hydra)).or_default().push(pkg);
 
         Ok(())
     }
 }
 
-impl HydraPackage {
-    async fn resolve(&self, base: &str, system: System) -> Result<BTreeMap<Rc<str>, StorePath>> {
-        let url = format!(
-            "{base}/job/{}/{}/{}/latest-for/{system}",
-            self.project, self.jobset, self.job,
-        );
+impl Jobset {
+    fn new(hydra: &HydraResolver) -> Self {
+        Self {
+            base: hydra.base.clone(),
+            project: hydra.project.clone(),
+            jobset: hydra.jobset.clone(),
+            eval: hydra.eval,
+            revision: hydra.revision.clone(),
+        }
+    }
 
-        debug!(url);
+    async fn resolve(
+        &self,
+        pkgs: &[HydraPackage],
+        locked: Option<u64>,
+        semaphore: &Rc<Semaphore>,
+    ) -> Result<(Eval, Vec<Outputs>)> {
+        let base = config::rewrite(&self.base);
 
-        let build: Build = HTTP_CLIENT
-            .request(Method::GET, url)
-            .header(ACCEPT, "application/json")
-            .send()
-            .await
-            .into_diagnostic()?
-            .json()
-            .await
-            .into_diagnostic()
-            .wrap_err_with(|| {
-                miette!(
-                    "failed to parse hydra response for {} on {system}",
-                    self.job,
-                )
-            })?;
-
-        match build {
-            Build::Ok { buildoutputs } => {
-                let mut outputs = buildoutputs
-                    .into_iter()
-                    .map(|(name, output)| Ok((name, StorePath::new(&output.path)?)))
-                    .collect::<Result<BTreeMap<_, _>>>()?;
-
-                if !self.outputs.is_empty() {
-                    outputs.retain(|name, _| self.outputs.contains(name.as_ref()));
+        let pinned = match (self.eval, &self.revision) {
+            (Some(id), _) => Some(get(&format!("{base}/eval/{id}")).await?),
+            (None, Some(revision)) => Some(self.find_revision(&base, revision).await?),
+            (None, None) => None,
+        };
+        if let Some(eval) = pinned {
+            let outputs = resolve_eval(&base, eval.id, pkgs, semaphore)
+                .await?
+                .map_err(|i| {
+                    miette!(
+                        "no successful build found for {} in evaluation {}",
+                        pkgs[i].job,
+                        eval.id,
+                    )
+                })?;
+            return Ok((eval, outputs));
+        }
+
+        // stay on the same evaluation as the packages that are already locked
+        if let Some(id) = locked {
+            let eval: Eval = get(&format!("{base}/eval/{id}")).await?;
+            let outputs = resolve_eval(&base, id, pkgs, semaphore)
+                .await?
+                .map_err(|i| {
+                    miette!(
+                        help =
+                            "run `unnix update` to resolve every package from a newer evaluation",
+                        "no successful build found for {} in evaluation {id}, \
+                     which the other packages are locked to",
+                        pkgs[i].job,
+                    )
+                })?;
+            return Ok((eval, outputs));
+        }
+
+        let mut page = self.evals(&base, None).await?;
+        let mut checked = 0;
+        loop {
+            for eval in page.evals {
+                match resolve_eval(&base, eval.id, pkgs, semaphore).await? {
+                    Ok(outputs) => return Ok((eval, outputs)),
+                    Err(i) => {
+                        debug!(
+                            "skipping evaluation {}, {} did not succeed",
+                            eval.id, pkgs[i].job
+                        );
+                    }
+                }
+
+                checked += 1;
+                if checked == MAX_EVALS {
+                    bail!(
+                        help = "pin an evaluation with the `eval` or `revision` field",
+                        "none of the latest {MAX_EVALS} evaluations of {}:{} built every package successfully",
+                        self.project,
+                        self.jobset,
+                    );
+                }
+            }
+
+            match page.next {
+                Some(next) => page = self.evals(&base, Some(&next)).await?,
+                None => bail!(
+                    "no evaluation of {}:{} built every package successfully",
+                    self.project,
+                    self.jobset,
+                ),
+            }
+        }
+    }
+
+    // abbreviated revisions are accepted, like in git,
+    // but every searched evaluation is checked so an ambiguous one is rejected
+    async fn find_revision(&self, base: &str, revision: &str) -> Result<Eval> {
+        let mut searched = 0;
+        let mut evaluated = Vec::new();
+        let mut found: Option<(Rc<str>, Eval)> = None;
+        let mut page = self.evals(base, None).await?;
+        loop {
+            for eval in page.evals {
+                searched += 1;
+                let Some(rev) = eval.revision() else {
+                    continue;
+                };
+                if !rev.starts_with(revision) {
+                    evaluated.push((eval.id, rev));
+                    continue;
                 }
+                match &found {
+                    None if rev.len() == revision.len() => return Ok(eval),
+                    None => found = Some((rev, eval)),
+                    // the same revision can be evaluated more than once, and the latest one is used
+                    Some((first, _)) if *first == rev => {}
+                    Some((first, _)) => bail!(
+                        help = "specify more characters of the revision",
+                        "revision {revision} is ambiguous, since it matches both {first} and {rev}",
+                    ),
+                }
+            }
 
-                Ok(outputs)
+            match page.next {
+                Some(next) if searched < MAX_REVISION_EVALS => {
+                    page = self.evals(base, Some(&next)).await?;
+                }
+                _ => break,
             }
+        }
+        if let Some((_, eval)) = found {
+            return Ok(eval);
+        }
+
+        let describe = |(id, rev): &(u64, Rc<str>)| format!("{rev} (evaluation {id})");
+        let help = match evaluated.as_slice() {
+            [] => "the jobset has no evaluations with a nixpkgs revision".into(),
+            [latest @ .., oldest] if latest.len() >= 3 => format!(
+                "the latest evaluated revisions are\n{}\nthe oldest evaluated revision searched is\n{}",
+                latest[.. 3].iter().map(describe).join("\n"),
+                describe(oldest),
+            ),
+            evaluated => format!(
+                "the latest evaluated revisions are\n{}",
+                evaluated.iter().map(describe).join("\n"),
+            ),
+        };
+        bail!(
+            help = help,
+            "revision {revision} was not found in the latest {searched} evaluations of {}:{}",
+            self.project,
+            self.jobset,
+        );
+    }
+
+    // evaluations are listed from newest to oldest
+    async fn evals(&self, base: &str, next: Option<&str>) -> Result<Evals> {
+        get(&format!(
+            "{base}/jobset/{}/{}/evals{}",
+            self.project,
+            self.jobset,
+            next.unwrap_or_default(),
+        ))
+        .await
+    }
+}
+
+// returns the index of the first job found without a successful build in the evaluation,
+// which stops the other jobs from being queried
+async fn resolve_eval(
+    base: &str,
+    eval: u64,
+    pkgs: &[HydraPackage],
+    semaphore: &Rc<Semaphore>,
+) -> Result<Result<Vec<Outputs>, usize>> {
+    let mut tasks = JoinSet::new();
+    for (i, pkg) in pkgs.iter().enumerate() {
+        let url = format!("{base}/eval/{eval}/job/{}", pkg.job);
+        let outputs = pkg.outputs.clone();
+        let semaphore = semaphore.clone();
+        tasks.spawn_local(async move {
+            let _permit = semaphore.acquire().await.into_diagnostic()?;
+            Result::<_>::Ok((i, resolve_build(&url, &outputs).await?))
+        });
+    }
+
+    let mut results = vec![Outputs::new(); pkgs.len()];
+    while let Some(res) = tasks.join_next().await {
+        let (i, outputs) = res.into_diagnostic()??;
+        let Some(outputs) = outputs else {
+            tasks.abort_all();
+            return Ok(Err(i));
+        };
+        results[i] = outputs;
+    }
+    Ok(Ok(results))
+}
 
-            Build::Err { error } => {
-                let e = miette!(error).wrap_err(format!(
-                    "no successful build found for {} on {system}",
-                    self.job,
-                ));
-                Err(e)
+async fn resolve_build(url: &str, wanted: &BTreeSet<String>) -> Result<Option<Outputs>> {
+    match get(url).await? {
+        Build::Ok {
+            buildstatus: Some(0),
+            buildoutputs,
+        } => {
+            let mut outputs = buildoutputs
+                .into_iter()
+                .map(|(name, output)| Ok((name, StorePath::new(&output.path)?)))
+                .collect::<Result<Outputs>>()?;
+
+            if !wanted.is_empty() {
+                outputs.retain(|name, _| wanted.contains(name.as_ref()));
             }
+
+            Ok(Some(outputs))
         }
+
+        Build::Ok { .. } => Ok(None),
+
+        Build::Err { error } => {
+            debug!("{url}: {error}");
+            Ok(None)
+        }
+    }
+}
+
+async fn get<T: DeserializeOwned>(url: &str) -> Result<T> {
+    debug!(url);
+
+    HTTP_CLIENT
+        .request(Method::GET, url)
+        .header(ACCEPT, "application/json")
+        .send()
+        .await
+        .into_diagnostic()?
+        .json()
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to parse hydra response from {url}"))
+}
+
+impl Eval {
+    // flakes are pinned to a revision in their url, e.g. github:NixOS/nixpkgs/<revision>
+    fn revision(&self) -> Option<Rc<str>> {
+        if let Some(input) = self.jobsetevalinputs.get("nixpkgs") {
+            return input.revision.clone();
+        }
+        let flake = self.flake.as_deref()?;
+        let flake = flake.split_once('?').map_or(flake, |(flake, _)| flake);
+        flake.rsplit_once('/').map(|(_, revision)| revision.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Eval;
+
+    #[test]
+    fn revision() {
+        let eval = |json| serde_json::from_str::<Eval>(json).unwrap().revision();
+        assert_eq!(
+            eval(
+                r#"{
+                    "id": 1,
+                    "jobsetevalinputs": {
+                        "nixpkgs": {
+                            "type": "git",
+                            "uri": "https://github.com/NixOS/nixpkgs.git",
+                            "revision": "0123456789abcdef0123456789abcdef01234567"
+                        }
+                    }
+                }"#,
+            )
+            .as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567"),
+        );
+        assert_eq!(
+            eval(
+                r#"{
+                    "id": 2,
+                    "flake": "github:NixOS/nixpkgs/0123456789abcdef0123456789abcdef01234567?narHash=sha256-AAAA"
+                }"#,
+            )
+            .as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567"),
+        );
+        assert_eq!(eval(r#"{"id": 3}"#), None);
     }
 }
@@ -1,5 +1,7 @@
+pub mod channel;
 pub mod devbox;
 pub mod hydra;
+pub mod r#static;
 
 use std::{collections::HashMap, rc::Rc};
 
@@ -11,11 +13,13 @@ use tracing::Span;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
 use crate::{
-    lockfile::Lockfile,
+    lockfile::{Lockfile, PackageLock},
     package::{Base64Hash, Package},
     resolver::{
+        channel::{ChannelJobs, ChannelResolver},
         devbox::{DevboxJobs, DevboxResolver},
         hydra::{HydraJobs, HydraResolver},
+        r#static::{StaticJobs, StaticResolver},
     },
     system::System,
 };
@@ -23,33 +27,66 @@ use crate::{
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum Resolver {
+    Channel(ChannelResolver),
     Devbox(DevboxResolver),
     Hydra(HydraResolver),
+    Static(StaticResolver),
 }
 
 pub struct ResolverJobs {
     span: Span,
+    channel: ChannelJobs,
     devbox: DevboxJobs,
     hydra: HydraJobs,
+    r#static: StaticJobs,
 }
 
 impl ResolverJobs {
     pub fn new(span: Span) -> Self {
         Self {
             span,
+            channel: ChannelJobs::default(),
             devbox: DevboxJobs::default(),
             hydra: HydraJobs::default(),
+            r#static: StaticJobs::default(),
         }
     }
 
     pub async fn resolve(self, lockfile: &Lockfile) -> Result<()> {
+        self.r#static.resolve(&self.span, lockfile);
         try_join!(
+            self.channel.resolve(&self.span, lockfile),
             self.devbox.resolve(&self.span, lockfile),
             self.hydra.resolve(&self.span, lockfile),
         )?;
         Ok(())
     }
 
+    // packages that are already locked, so new packages can be resolved consistently with them
+    pub fn keep(&mut self, pkg: &Package, system: System, lock: &PackageLock) -> Result<()> {
+        match pkg.resolver.as_ref() {
+            Resolver::Channel(channel) => {
+                if let Some(release) = lock
+                    .source
+                    .as_ref()
+                    .and_then(|source| source.channel.as_ref())
+                {
+                    self.channel.keep(channel, system, release)?;
+                }
+            }
+
+            Resolver::Hydra(hydra) => {
+                if let Some(eval) = lock.source.as_ref().and_then(|source| source.eval) {
+                    self.hydra.keep(hydra, eval);
+                }
+            }
+
+            Resolver::Devbox(_) | Resolver::Static(_) => {}
+        }
+
+        Ok(())
+    }
+
     pub fn add(
         &mut self,
         name: Rc<str>,
@@ -60,6 +97,17 @@ impl ResolverJobs {
         self.span.pb_inc_length(1);
 
         match pkg.resolver.as_ref() {
+            Resolver::Channel(channel) => {
+                self.channel.add(
+                    channel,
+                    name,
+                    key,
+                    &pkg.package,
+                    system,
+                    pkg.outputs.clone(),
+                )?;
+            }
+
             Resolver::Devbox(devbox) => {
                 self.devbox
                     .add(devbox, name, key, &pkg.package, system, pkg.outputs.clone())?;
@@ -69,6 +117,11 @@ impl ResolverJobs {
                 self.hydra
                     .add(hydra, name, key, &pkg.package, system, pkg.outputs.clone())?;
             }
+
+            Resolver::Static(r#static) => {
+                self.r#static
+                    .add(r#static, name, key, &pkg.package, system, &pkg.outputs)?;
+            }
         }
 
         Ok(())
@@ -82,6 +135,8 @@ impl Default for Resolver {
             project: "nixpkgs".into(),
             jobset: "unstable".into(),
             job: "{package}.{system}".into(),
+            eval: None,
+            revision: None,
         })
     }
 }
@@ -0,0 +1,74 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+use miette::{Result, bail};
+use serde::Serialize;
+use tracing::Span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use crate::{
+    lockfile::{Lockfile, PackageLock},
+    package::Base64Hash,
+    store::path::StorePath,
+    system::System,
+};
+
+#[derive(Default)]
+pub struct StaticJobs {
+    jobs: Vec<(System, Rc<str>, PackageLock)>,
+}
+
+// store paths written in the manifest, usually per system since they differ between systems
+#[derive(Debug, Serialize)]
+pub struct StaticResolver {
+    pub packages: BTreeMap<String, BTreeMap<Rc<str>, StorePath>>,
+}
+
+impl StaticJobs {
+    // no network access is needed, so everything is written to the lockfile right away
+    pub fn resolve(self, span: &Span, lockfile: &Lockfile) {
+        for (system, name, lock) in self.jobs {
+            lockfile.systems[&system].inner.insert(name, lock);
+            span.pb_inc(1);
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        resolver: &StaticResolver,
+        name: Rc<str>,
+        key: Base64Hash,
+        package: &str,
+        system: System,
+        outputs: &BTreeSet<String>,
+    ) -> Result<()> {
+        let Some(paths) = resolver.packages.get(package) else {
+            bail!("{package} has no store paths for {system}");
+        };
+
+        let outputs = if outputs.is_empty() {
+            paths.clone()
+        } else {
+            outputs
+                .iter()
+                .map(|output| match paths.get(output.as_str()) {
+                    Some(path) => Ok((output.as_str().into(), path.clone())),
+                    None => bail!("output {output} of {package} has no store path for {system}"),
+                })
+                .collect::<Result<_>>()?
+        };
+
+        self.jobs.push((
+            system,
+            name,
+            PackageLock {
+                key,
+                outputs,
+                source: None,
+            },
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    convert::Infallible,
+    fmt::Write as _,
+    io::{self, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_compression::tokio::bufread::ZstdEncoder;
+use bytes::Bytes;
+use harmonia_store_core::signature::SecretKey;
+use harmonia_utils_hash::fmt::CommonHash;
+use http_body_util::{BodyExt, Full, StreamBody, combinators::UnsyncBoxBody};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Frame, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result};
+use nix_nar::Encoder;
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, BufReader, DuplexStream, duplex},
+    net::TcpListener,
+    runtime::Handle,
+    spawn,
+    task::spawn_blocking,
+    time::timeout,
+};
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+
+use crate::store::{Store, nar::fingerprint, path::StorePath};
+
+type Body = UnsyncBoxBody<Bytes, io::Error>;
+
+// nars are served with the gc lock held, so a client that stops reading is dropped
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Server {
+    store: Store,
+    key: SecretKey,
+}
+
+// writes from a blocking task, failing when the reader doesn't make progress in time
+struct TimeoutWriter {
+    writer: DuplexStream,
+    handle: Handle,
+    timeout: Duration,
+}
+
+pub async fn serve(store: Store, key: SecretKey, listener: TcpListener) -> Result<()> {
+    info!(
+        "serving {} on http://{}",
+        store.path,
+        listener.local_addr().into_diagnostic()?,
+    );
+
+    let server = Arc::new(Server { store, key });
+
+    loop {
+        let (stream, addr) = listener.accept().await.into_diagnostic()?;
+        let server = server.clone();
+
+        spawn(async move {
+            let service = service_fn(|req| server.clone().handle(req));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("connection from {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+impl Server {
+    async fn handle(self: Arc<Self>, req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+
+        let path = req.uri().path();
+        debug!("{} {path}", req.method());
+
+        let res = if path == "/nix-cache-info" {
+            Ok(Some(text(
+                "text/x-nix-cache-info",
+                "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 30\n".into(),
+            )))
+        } else if let Some(hash) = path
+            .strip_prefix('/')
+            .and_then(|path| path.strip_suffix(".narinfo"))
+        {
+            self.narinfo(hash).await
+        } else if let Some(file) = path.strip_prefix("/nar/") {
+            if let Some(hash) = file.strip_suffix(".nar.zst") {
+                self.nar(hash, true).await
+            } else if let Some(hash) = file.strip_suffix(".nar") {
+                self.nar(hash, false).await
+            } else {
+                Ok(None)
+            }
+        } else {
+            Ok(None)
+        };
+
+        Ok(match res {
+            Ok(Some(res)) => res,
+            Ok(None) => status(StatusCode::NOT_FOUND),
+            Err(e) => {
+                warn!("failed to serve {path}: {e:?}");
+                status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        })
+    }
+
+    // no gc lock is needed, since a path that is deleted afterwards is simply missing its nar
+    async fn narinfo(&self, hash: &str) -> Result<Option<Response<Body>>> {
+        let Some(path) = self.find(hash).await? else {
+            return Ok(None);
+        };
+        // store paths that are not registered might not have been fully pulled yet
+        let Some(mut info) = self.store.query_path_info(&path).await? else {
+            return Ok(None);
+        };
+
+        info.references.sort();
+        let nar_hash = info.nar_hash.as_base32().to_string();
+        let nar_size = info.nar_size as usize;
+        let sig = self
+            .key
+            .sign(fingerprint(&path, &nar_hash, nar_size, &info.references)?);
+
+        // signatures from upstream caches are kept, since they sign the same fingerprint
+        let mut narinfo = format!(
+            "StorePath: /nix/store/{path}\n\
+             URL: nar/{hash}.nar.zst\n\
+             Compression: zstd\n\
+             NarHash: {nar_hash}\n\
+             NarSize: {nar_size}\n\
+             References: {}\n",
+            info.references.iter().join(" "),
+        );
+        if let Some(deriver) = info.deriver {
+            writeln!(narinfo, "Deriver: {deriver}").into_diagnostic()?;
+        }
+        for sig in info.sigs {
+            writeln!(narinfo, "Sig: {sig}").into_diagnostic()?;
+        }
+        writeln!(narinfo, "Sig: {sig}").into_diagnostic()?;
+
+        Ok(Some(text("text/x-nix-narinfo", narinfo)))
+    }
+
+    async fn nar(&self, hash: &str, compress: bool) -> Result<Option<Response<Body>>> {
+        let lock = self.store.lock_gc_shared().await?;
+        let Some(path) = self.find(hash).await? else {
+            return Ok(None);
+        };
+
+        let path = self.store.path.join(&path);
+        let (reader, writer) = duplex(64 * 1024);
+        let mut writer = TimeoutWriter {
+            writer,
+            handle: Handle::current(),
+            timeout: STALL_TIMEOUT,
+        };
+        spawn_blocking(move || {
+            // keep the store path from being garbage collected while it is being served,
+            // which is bounded by the stall timeout if the client stops reading
+            let _lock = lock;
+            let res = Encoder::new(&path)
+                .into_diagnostic()
+                .and_then(|mut nar| io::copy(&mut nar, &mut writer).into_diagnostic());
+            if let Err(e) = res {
+                warn!("failed to serve {path}: {e:?}");
+            }
+        });
+
+        let reader: Box<dyn AsyncRead + Send + Unpin> = if compress {
+            Box::new(ZstdEncoder::new(BufReader::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+        let body = StreamBody::new(ReaderStream::new(reader).map(|chunk| chunk.map(Frame::data)));
+
+        let mut res = Response::new(body.boxed_unsync());
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            "application/x-nix-nar".parse().into_diagnostic()?,
+        );
+        Ok(Some(res))
+    }
+
+    async fn find(&self, hash: &str) -> Result<Option<StorePath>> {
+        if hash.len() != 32 || !hash.bytes().all(|c| c.is_ascii_alphanumeric()) {
+            return Ok(None);
+        }
+        self.store.find(hash).await
+    }
+}
+
+impl Write for TimeoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle
+            .block_on(timeout(self.timeout, self.writer.write(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client stopped reading"))?
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle
+            .block_on(timeout(self.timeout, self.writer.flush()))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client stopped reading"))?
+    }
+}
+
+fn text(content_type: &'static str, body: String) -> Response<Body> {
+    let mut res = Response::new(
+        Full::new(body.into())
+            .map_err(|e| match e {})
+            .boxed_unsync(),
+    );
+    res.headers_mut()
+        .insert(CONTENT_TYPE, content_type.try_into().unwrap());
+    res
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut res = text("text/plain", format!("{status}\n"));
+    *res.status_mut() = status;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{create_dir, read_to_string, write},
+        io::{ErrorKind, Write},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use camino::Utf8Path;
+    use harmonia_store_core::signature::SecretKey;
+    use harmonia_utils_hash::Algorithm;
+    use ring::rand::SystemRandom;
+    use tempfile::TempDir;
+    use tokio::{
+        io::duplex, net::TcpListener, runtime::Handle, spawn, sync::OnceCell, task::spawn_blocking,
+    };
+
+    use super::{TimeoutWriter, serve};
+    use crate::{
+        cache::{Cache, get_nar, get_narinfo, parse_url},
+        store::{PathInfo, Store, nar::Narinfo, path::StorePath},
+    };
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let tmp = TempDir::new().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let server = Store::new(&root.join("server")).unwrap();
+        let client = Store::new(&root.join("client")).unwrap();
+
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let glibc =
+            StorePath::from_storeless("5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66").unwrap();
+        create_dir(server.path.join(&path)).unwrap();
+        write(server.path.join(&path).join("hello"), "world").unwrap();
+        let (nar_hash, nar_size) = server.nar_hash(&path, Algorithm::SHA256).await.unwrap();
+        let info = PathInfo {
+            nar_hash,
+            nar_size,
+            references: vec![glibc.clone()],
+            deriver: None,
+            sigs: Vec::new(),
+            cache: None,
+        };
+        server.register(&path, &info).await.unwrap();
+
+        let key = SecretKey::generate("test-1".into(), &SystemRandom::new()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cache = Cache {
+            url: parse_url(&format!("http://{}", listener.local_addr().unwrap())).unwrap(),
+            public_keys: vec![Arc::new(key.to_public_key())],
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new(),
+        };
+        spawn(serve(server, key, listener));
+
+        // glibc is not in the store
+        assert!(get_narinfo(&cache, &glibc).await.unwrap().is_none());
+
+        let narinfo = get_narinfo(&cache, &path).await.unwrap().unwrap();
+        let narinfo = Narinfo::parse(&narinfo, &path, &cache).unwrap();
+        assert_eq!(narinfo.references, [glibc]);
+
+        client
+            .unpack_nar(
+                &path,
+                get_nar(&cache, &narinfo.url).await.unwrap(),
+                narinfo.compression,
+                narinfo.nar_hash,
+                narinfo.nar_size,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            read_to_string(client.path.join(&path).join("hello")).unwrap(),
+            "world",
+        );
+    }
+
+    #[tokio::test]
+    async fn stall() {
+        let (_reader, writer) = duplex(16);
+        let mut writer = TimeoutWriter {
+            writer,
+            handle: Handle::current(),
+            timeout: Duration::from_millis(10),
+        };
+        let e = spawn_blocking(move || writer.write_all(&[0; 32]))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -1,29 +1,29 @@
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
-    io::Cursor,
     process::Command,
     rc::Rc,
     sync::{Arc, LazyLock},
 };
 
 use camino::{Utf8Path, Utf8PathBuf};
-use harmonia_store_core::signature::PublicKey;
+use harmonia_utils_hash::fmt::CommonHash;
 use miette::{IntoDiagnostic, Result, bail, miette};
-use reqwest::{Client, StatusCode};
+use reqwest::Client;
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{Jitter, RetryTransientMiddleware, policies::ExponentialBackoff};
 use strfmt::strfmt;
 use tokio::{select, sync::mpsc, task::JoinSet, try_join};
 use tracing::{debug, field::Empty, info, info_span, warn};
 use tracing_indicatif::{span_ext::IndicatifSpanExt, style::ProgressStyle};
-use url::Url;
 
 use crate::{
+    cache::{self, Cache, FileMismatch},
     cli::GlobalArgs,
+    config,
     lockfile::{Lockfile, SystemLockfile},
     manifest::{Manifest, SystemManifest},
     resolver::ResolverJobs,
-    store::{Store, nar::Narinfo, path::StorePath},
+    store::{PathInfo, Store, nar::Narinfo, path::StorePath},
     system::System,
 };
 
@@ -35,6 +35,9 @@ pub struct State {
     pub system: System,
 }
 
+// how many times a nar is downloaded again if it does not match FileHash or FileSize
+const FILE_MISMATCH_RETRIES: usize = 3;
+
 pub static HTTP_CLIENT: LazyLock<ClientWithMiddleware> = LazyLock::new(|| {
     let client = Client::builder()
         .user_agent(concat!("unnix/", env!("CARGO_PKG_VERSION")))
@@ -52,18 +55,30 @@ pub static HTTP_CLIENT: LazyLock<ClientWithMiddleware> = LazyLock::new(|| {
 
 impl State {
     pub fn new(global: GlobalArgs, system: Option<System>) -> Result<Self> {
+        // cache urls are rewritten when the manifest is loaded
+        config::load(global.config.as_deref())?;
+
         let dir = global.directory.unwrap_or_else(|| ".".into());
         let manifest = Manifest::from_dir(&dir)?;
         let system = match system {
             Some(system) => system,
             None => System::host()?,
         };
+        let root = match global.root.or_else(|| manifest.root(&dir)) {
+            Some(root) => root,
+            None => Store::default_root()?,
+        };
+
+        let mut store = Store::new(&root)?;
+        store.auto_optimise = manifest.store.auto_optimise;
+
+        cache::netrc::load(global.netrc_file.as_deref())?;
 
         Ok(Self {
             dir,
             lockfile: Lockfile::default(),
             manifest,
-            store: Arc::new(Store::new()?),
+            store: Arc::new(store),
             system,
         })
     }
@@ -127,17 +142,24 @@ impl State {
         span.pb_set_length(0);
         span.pb_start();
 
+        let _lock = self.store.lock_gc_shared().await?;
+        self.store
+            .add_root(&self.dir.join("unnix.lock.json"))
+            .await?;
+
         let (tx, mut rx) = mpsc::unbounded_channel();
         tx.send(paths).map_err(|_| miette!("channel closed"))?;
 
         let mut downloaded = BTreeSet::new();
+        // store paths that were already valid, whose last access times are updated at once
+        let mut used = Vec::new();
         let mut tasks = JoinSet::new();
         let worker_style = Arc::new(ProgressStyle::with_template("  ‣ {msg}").into_diagnostic()?);
 
         loop {
             let join_all = async {
                 while let Some(res) = tasks.join_next().await {
-                    res.into_diagnostic()??;
+                    used.extend(res.into_diagnostic()??);
                 }
                 Result::<_>::Ok(())
             };
@@ -165,7 +187,6 @@ impl State {
                 }
 
                 let caches = manifest.caches.clone();
-                let public_keys = manifest.public_keys.clone();
                 let span = span.clone();
                 let store = self.store.clone();
                 let tx = tx.clone();
@@ -175,10 +196,10 @@ impl State {
                     let _lock = store.lock_path(&path).await?;
 
                     if store.path.join(&path).symlink_metadata().is_ok()
-                        && let Some(references) = store.get_references(path.hash()).await?
+                        && let Some(references) = store.get_references(&path).await?
                     {
                         tx.send(references).map_err(|_| miette!("channel closed"))?;
-                        return Ok(());
+                        return Ok(Some(path));
                     }
 
                     span.pb_inc_length(1);
@@ -187,39 +208,127 @@ impl State {
                     worker.pb_set_message(path.as_str());
                     worker.pb_start();
 
-                    let (cache, narinfo) = query(&path, caches, &public_keys).await?;
-                    let nar = cache.join(&narinfo.url).into_diagnostic()?;
+                    // the host store is only an optimization, so the caches are used if it fails
+                    if let Some(host) = &store.host {
+                        match host.query_path_info(&path).await {
+                            Ok(Some(info)) => {
+                                tx.send(info.references.clone())
+                                    .map_err(|_| miette!("channel closed"))?;
+                                store.copy_from_host(host, &path, &info).await?;
+                                info!("copied {path} from /nix/store");
+                                span.pb_inc(1);
+                                return Ok(None);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                debug!("failed to query {path} in /nix/store: {e:?}");
+                            }
+                        }
+                    }
+
+                    let (cache, narinfo) = query(&store, &path, &caches, true).await?;
                     tx.send(narinfo.references.clone())
                         .map_err(|_| miette!("channel closed"))?;
 
-                    let put_references = store.put_references(path.hash(), &narinfo.references);
-                    let unpack_nar = async {
-                        let nar = HTTP_CLIENT
-                            .get(nar)
-                            .send()
-                            .await
-                            .into_diagnostic()?
-                            .bytes()
-                            .await
-                            .into_diagnostic()?;
+                    download(&store, &path, &cache, narinfo).await?;
+                    info!("downloaded {path} from {cache}");
+                    span.pb_inc(1);
+                    Result::<_>::Ok(None)
+                });
+            }
+        }
 
-                        store
-                            .unpack_nar(
-                                &path,
-                                Cursor::new(nar),
-                                narinfo.compression,
-                                narinfo.nar_hash,
-                                narinfo.nar_size,
-                            )
-                            .await
+        self.store.touch(used).await
+    }
+
+    pub async fn verify(&self, repair: bool) -> Result<()> {
+        let Some(manifest) = self.manifest.systems.get(&self.system) else {
+            bail!("system {} not supported by the manifest", self.system);
+        };
+
+        let span = info_span!("verify", indicatif.pb_show = Empty);
+        span.pb_set_message("verifying store paths");
+        span.pb_set_length(0);
+        span.pb_start();
+
+        let _lock = self.store.lock_gc_shared().await?;
+
+        let mut paths = self.lockfile.collect_outputs(&self.system);
+        let mut checked = BTreeSet::new();
+        let mut invalid = 0;
+        let mut tasks = JoinSet::new();
+
+        while !paths.is_empty() {
+            for path in paths.drain(..) {
+                if !checked.insert(path.clone()) {
+                    continue;
+                }
+
+                let caches = manifest.caches.clone();
+                let span = span.clone();
+                let store = self.store.clone();
+
+                span.pb_inc_length(1);
+                tasks.spawn(async move {
+                    let _lock = store.lock_path(&path).await?;
+                    let info = store.query_path_info(&path).await?;
+
+                    let valid = if let Some(info) = &info {
+                        if store.path.join(&path).symlink_metadata().is_err() {
+                            warn!("{path} is missing from the store");
+                            false
+                        } else {
+                            let (nar_hash, nar_size) =
+                                store.nar_hash(&path, info.nar_hash.algorithm()).await?;
+                            if nar_hash == info.nar_hash && nar_size == info.nar_size {
+                                true
+                            } else {
+                                warn!(
+                                    "{path} was modified\nexpected: {}\n  actual: {}",
+                                    info.nar_hash.sri(),
+                                    nar_hash.sri(),
+                                );
+                                false
+                            }
+                        }
+                    } else {
+                        warn!("{path} is not registered in the store database");
+                        false
+                    };
+
+                    let references = if !valid && repair {
+                        let (cache, narinfo) = query(&store, &path, &caches, false).await?;
+                        let references = narinfo.references.clone();
+                        download(&store, &path, &cache, narinfo).await?;
+                        info!("repaired {path} from {cache}");
+                        references
+                    } else {
+                        info.map(|info| info.references).unwrap_or_default()
                     };
 
-                    try_join!(put_references, unpack_nar)?;
-                    info!("downloaded {path} from {cache}");
                     span.pb_inc(1);
-                    Result::<_>::Ok(())
+                    Result::<_>::Ok((references, valid))
                 });
             }
+
+            while let Some(res) = tasks.join_next().await {
+                let (references, valid) = res.into_diagnostic()??;
+                paths.extend(references);
+                if !valid {
+                    invalid += 1;
+                }
+            }
+        }
+
+        if invalid == 0 {
+            info!("verified {} store paths", checked.len());
+        } else if repair {
+            info!("repaired {invalid} of {} store paths", checked.len());
+        } else {
+            bail!(
+                "{invalid} of {} store paths failed verification, rerun with --repair to fix them",
+                checked.len(),
+            );
         }
 
         Ok(())
@@ -305,6 +414,12 @@ impl State {
         Ok(cmd)
     }
 
+    // register the lockfile as a garbage collection root
+    pub async fn add_root(&self) -> Result<()> {
+        let _lock = self.store.lock_gc_shared().await?;
+        self.store.add_root(&self.dir.join("unnix.lock.json")).await
+    }
+
     async fn lock(&mut self) -> Result<()> {
         let span = info_span!("lock", indicatif.pb_show = Empty);
         span.pb_set_message("generating lockfile");
@@ -322,6 +437,7 @@ impl State {
                     && let Some((name, old)) = old.inner.remove(name)
                     && old.key == key
                 {
+                    jobs.keep(pkg, system, &old)?;
                     lockfile.inner.insert(name, old);
                 } else {
                     jobs.add(name.clone(), key, pkg, system)?;
@@ -331,7 +447,8 @@ impl State {
         }
 
         jobs.resolve(&self.lockfile).await?;
-        self.lockfile.write_dir(&self.dir)
+        self.lockfile.write_dir(&self.dir)?;
+        self.add_root().await
     }
 
     async fn locked(&mut self) -> Result<bool> {
@@ -364,44 +481,201 @@ impl State {
     }
 }
 
+// mass_query is set when querying whole closures, where caches without WantMassQuery are skipped,
+// unless none of the other caches have the store path
 async fn query(
+    store: &Store,
     path: &StorePath,
-    caches: Vec<Arc<Url>>,
-    public_keys: &[Arc<PublicKey>],
-) -> Result<(Arc<Url>, Narinfo)> {
+    caches: &[Arc<Cache>],
+    mass_query: bool,
+) -> Result<(Arc<Cache>, Narinfo)> {
+    let selected = cache::select(caches, mass_query).await?;
+    if let Some(hit) = query_caches(store, path, &selected).await? {
+        return Ok(hit);
+    }
+
+    if mass_query {
+        let rest: Vec<_> = cache::select(caches, false)
+            .await?
+            .into_iter()
+            .filter(|cache| !selected.iter().any(|selected| Arc::ptr_eq(selected, cache)))
+            .collect();
+        if let Some(hit) = query_caches(store, path, &rest).await? {
+            return Ok(hit);
+        }
+    }
+
+    bail!("{path} could not be found in any cache");
+}
+
+// all caches are queried at once, and the hit from the cache with the highest priority wins
+// narinfos that fail to parse or verify are skipped, so a cache with a lower priority can be used
+async fn query_caches(
+    store: &Store,
+    path: &StorePath,
+    caches: &[Arc<Cache>],
+) -> Result<Option<(Arc<Cache>, Narinfo)>> {
+    let hash = path.hash();
+
+    // None until the cache responds, then Some(None) on misses
+    let mut results = Vec::with_capacity(caches.len());
     for cache in caches {
+        // local caches are as fast as the narinfo cache
+        results.push(if cache.url.scheme() == "file" {
+            None
+        } else {
+            match store.query_narinfo(&cache.url, hash).await? {
+                Some(Some(content)) => match Narinfo::parse(&content, path, cache) {
+                    Ok(narinfo) => Some(Some(narinfo)),
+                    // the cached narinfo might no longer verify if the keys of the cache changed,
+                    // so the cache is queried again
+                    Err(e) => {
+                        debug!("cached narinfo for {path} from {cache} is invalid: {e}");
+                        None
+                    }
+                },
+                Some(None) => Some(None),
+                None => None,
+            }
+        });
+    }
+
+    // caches with lower priorities than a cached hit don't need to be queried
+    let end = results
+        .iter()
+        .position(|res| matches!(res, Some(Some(_))))
+        .unwrap_or(caches.len());
+    let mut tasks = JoinSet::new();
+    let mut handles = Vec::new();
+    for (i, cache) in caches.iter().enumerate().take(end) {
+        if results[i].is_some() {
+            continue;
+        }
+        let cache = cache.clone();
+        let path = path.clone();
         debug!("checking {path} on {cache}");
-        match query_one(path.hash(), &cache).await {
-            Ok(Some(narinfo)) => {
-                return Ok((cache, Narinfo::parse(&narinfo, public_keys)?));
+        let handle = tasks.spawn(async move { (i, cache::get_narinfo(&cache, &path).await) });
+        handles.push((i, handle));
+    }
+
+    let mut next = 0;
+    loop {
+        while let Some(Some(res)) = results.get_mut(next) {
+            if let Some(narinfo) = res.take() {
+                return Ok(Some((caches[next].clone(), narinfo)));
+            }
+            next += 1;
+        }
+
+        let Some(res) = tasks.join_next().await else {
+            break;
+        };
+        let (i, res) = match res {
+            Err(e) if e.is_cancelled() => continue,
+            res => res.into_diagnostic()?,
+        };
+
+        let cache = &caches[i];
+        results[i] = Some(match res {
+            Ok(Some(content)) => match Narinfo::parse(&content, path, cache) {
+                Ok(narinfo) => {
+                    if cache.url.scheme() != "file" {
+                        store
+                            .cache_narinfo(&cache.url, hash, Some(&content))
+                            .await?;
+                    }
+                    // caches with lower priorities can no longer win
+                    for (_, handle) in handles.iter().filter(|(j, _)| *j > i) {
+                        handle.abort();
+                    }
+                    Some(narinfo)
+                }
+                Err(e) => {
+                    warn!("invalid narinfo for {path} from {cache}: {e}");
+                    None
+                }
+            },
+            Ok(None) => {
+                if cache.url.scheme() != "file" {
+                    store.cache_narinfo(&cache.url, hash, None).await?;
+                }
+                None
             }
-            Ok(None) => {}
             Err(e) => {
                 warn!("{e}");
+                None
             }
+        });
+    }
+
+    Ok(None)
+}
+
+// any existing store entry is assumed to be invalid and replaced once the download is verified
+async fn download(store: &Store, path: &StorePath, cache: &Cache, narinfo: Narinfo) -> Result<()> {
+    // corrupted downloads are retried, since they are usually caused by flaky connections
+    let mut attempts = 0;
+    loop {
+        let (nar, check) = cache::get_nar_checked(cache, &narinfo).await?;
+        let res = store
+            .unpack_nar(
+                path,
+                nar,
+                narinfo.compression,
+                narinfo.nar_hash,
+                narinfo.nar_size,
+            )
+            .await;
+
+        match check.finish(res).await {
+            Ok(()) => break,
+            Err(e)
+                if attempts < FILE_MISMATCH_RETRIES
+                    && e.downcast_ref::<FileMismatch>().is_some() =>
+            {
+                attempts += 1;
+                warn!("{e}");
+            }
+            Err(e) => return Err(e),
         }
     }
 
-    bail!("{path} could not be found in any cache");
+    // recursive sha256 content addresses are the nar hash, which is already verified
+    if let Some(ca) = &narinfo.ca
+        && ca.hash() != narinfo.nar_hash
+        && let Err(e) = store.verify_ca(path, ca).await
+    {
+        store.delete(path).await?;
+        return Err(e);
+    }
+
+    let info = PathInfo {
+        nar_hash: narinfo.nar_hash,
+        nar_size: narinfo.nar_size as u64,
+        references: narinfo.references,
+        deriver: narinfo.deriver,
+        sigs: narinfo.sigs,
+        cache: Some(cache.url.to_string()),
+    };
+    store.register(path, &info).await
 }
 
-async fn query_one(hash: &str, cache: &Url) -> Result<Option<String>> {
-    let res = HTTP_CLIENT
-        .get(cache.join(&format!("{hash}.narinfo")).into_diagnostic()?)
-        .send()
-        .await
-        .into_diagnostic()?;
+// the unnix root for commands that are not tied to any particular project,
+// where the manifest is optional
+pub fn standalone_root(global: GlobalArgs) -> Result<Utf8PathBuf> {
+    if let Some(root) = global.root {
+        return Ok(root);
+    }
 
-    if res.status() == StatusCode::NOT_FOUND {
-        Ok(None)
+    let dir = global.directory.unwrap_or_else(|| ".".into());
+    let root = if dir.join("unnix.kdl").exists() {
+        Manifest::from_dir(&dir)?.root(&dir)
     } else {
-        Ok(Some(
-            res.error_for_status()
-                .into_diagnostic()?
-                .text()
-                .await
-                .into_diagnostic()?,
-        ))
+        None
+    };
+    match root {
+        Some(root) => Ok(root),
+        None => Store::default_root(),
     }
 }
 
@@ -413,3 +687,182 @@ fn swap_remove_eq(xs: &mut Vec<String>, value: &str) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{create_dir, write},
+        sync::Arc,
+    };
+
+    use camino::Utf8Path;
+    use harmonia_store_core::signature::SecretKey;
+    use harmonia_utils_hash::Algorithm;
+    use ring::rand::SystemRandom;
+    use tempfile::TempDir;
+    use tokio::{net::TcpListener, spawn, sync::OnceCell};
+
+    use super::query;
+    use crate::{
+        cache::{Cache, CacheInfo, parse_url},
+        serve::serve,
+        store::{PathInfo, Store, nar::fingerprint, path::StorePath},
+    };
+
+    fn narinfo(key: &SecretKey, path: &StorePath, url: &str) -> String {
+        let nar_hash = "sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw";
+        let sig = key.sign(fingerprint(path, nar_hash, 1, &[]).unwrap());
+        format!(
+            "StorePath: /nix/store/{path}\nURL: {url}\nCompression: none\n\
+             NarHash: {nar_hash}\nNarSize: 1\nReferences: \nSig: {sig}\n",
+        )
+    }
+
+    #[tokio::test]
+    async fn priority() {
+        let tmp = TempDir::new().unwrap();
+        let store = Store::new(&Utf8Path::from_path(tmp.path()).unwrap().join("root")).unwrap();
+        let key = SecretKey::generate("test-1".into(), &SystemRandom::new()).unwrap();
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+
+        let cache = |name, priority, hit| {
+            let dir = tmp.path().join(name);
+            create_dir(&dir).unwrap();
+            write(dir.join("nix-cache-info"), "WantMassQuery: 1\n").unwrap();
+            if hit {
+                write(
+                    dir.join(format!("{}.narinfo", path.hash())),
+                    narinfo(&key, &path, &format!("nar/{name}.nar")),
+                )
+                .unwrap();
+            }
+            Arc::new(Cache {
+                url: parse_url(dir.to_str().unwrap()).unwrap(),
+                public_keys: vec![Arc::new(key.to_public_key())],
+                threshold: 1,
+                priority: Some(priority),
+                want_mass_query: None,
+                token_env: None,
+                info: OnceCell::new(),
+            })
+        };
+
+        let caches = [
+            cache("c", 30, true),
+            cache("a", 10, false),
+            cache("b", 20, true),
+        ];
+        let (_, hit) = query(&store, &path, &caches, true).await.unwrap();
+        assert_eq!(hit.url, "nar/b.nar");
+
+        // narinfos that don't verify fall back to caches with lower priorities
+        let other = SecretKey::generate("test-1".into(), &SystemRandom::new()).unwrap();
+        let d = cache("d", 5, false);
+        write(
+            tmp.path().join(format!("d/{}.narinfo", path.hash())),
+            narinfo(&other, &path, "nar/d.nar"),
+        )
+        .unwrap();
+        let (_, hit) = query(&store, &path, &[d, caches[2].clone()], true)
+            .await
+            .unwrap();
+        assert_eq!(hit.url, "nar/b.nar");
+
+        // caches without WantMassQuery are only used for closures if no other cache has the path
+        let e = cache("e", 1, true);
+        let e = Arc::new(Cache {
+            url: e.url.clone(),
+            public_keys: e.public_keys.clone(),
+            threshold: 1,
+            priority: e.priority,
+            want_mass_query: Some(false),
+            token_env: None,
+            info: OnceCell::new(),
+        });
+        let caches = [caches[0].clone(), caches[1].clone(), e.clone()];
+        let (_, hit) = query(&store, &path, &caches, true).await.unwrap();
+        assert_eq!(hit.url, "nar/c.nar");
+        let (_, hit) = query(&store, &path, &caches, false).await.unwrap();
+        assert_eq!(hit.url, "nar/e.nar");
+        let (_, hit) = query(&store, &path, &caches[1 ..], true).await.unwrap();
+        assert_eq!(hit.url, "nar/e.nar");
+
+        let missing =
+            StorePath::from_storeless("00000000000000000000000000000000-missing").unwrap();
+        assert!(query(&store, &missing, &caches, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn narinfo_cache() {
+        let tmp = TempDir::new().unwrap();
+        let store = Store::new(&Utf8Path::from_path(tmp.path()).unwrap().join("root")).unwrap();
+        let key = SecretKey::generate("test-1".into(), &SystemRandom::new()).unwrap();
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let missing =
+            StorePath::from_storeless("00000000000000000000000000000000-missing").unwrap();
+
+        // the cache is unreachable, so only the narinfo cache can answer
+        let cache = Arc::new(Cache {
+            url: parse_url("https://cache.invalid").unwrap(),
+            public_keys: vec![Arc::new(key.to_public_key())],
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new_with(Some(CacheInfo::default())),
+        });
+        let content = narinfo(&key, &path, "nar/cached.nar");
+        store
+            .cache_narinfo(&cache.url, path.hash(), Some(&content))
+            .await
+            .unwrap();
+        store
+            .cache_narinfo(&cache.url, missing.hash(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.query_narinfo(&cache.url, path.hash()).await.unwrap(),
+            Some(Some(content.clone())),
+        );
+        let caches = [cache];
+        let (_, narinfo) = query(&store, &path, &caches, true).await.unwrap();
+        assert_eq!(narinfo.url, "nar/cached.nar");
+        assert!(query(&store, &missing, &caches, true).await.is_err());
+
+        // the cached narinfo no longer verifies after the key of the cache changed,
+        // so the narinfo is fetched again
+        let server = Store::new(&Utf8Path::from_path(tmp.path()).unwrap().join("server")).unwrap();
+        create_dir(server.path.join(&path)).unwrap();
+        let (nar_hash, nar_size) = server.nar_hash(&path, Algorithm::SHA256).await.unwrap();
+        let info = PathInfo {
+            nar_hash,
+            nar_size,
+            references: Vec::new(),
+            deriver: None,
+            sigs: Vec::new(),
+            cache: None,
+        };
+        server.register(&path, &info).await.unwrap();
+        let other = SecretKey::generate("test-2".into(), &SystemRandom::new()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cache = Arc::new(Cache {
+            url: parse_url(&format!("http://{}", listener.local_addr().unwrap())).unwrap(),
+            public_keys: vec![Arc::new(other.to_public_key())],
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new_with(Some(CacheInfo::default())),
+        });
+        spawn(serve(server, other, listener));
+        store
+            .cache_narinfo(&cache.url, path.hash(), Some(&content))
+            .await
+            .unwrap();
+        let (_, narinfo) = query(&store, &path, &[cache], false).await.unwrap();
+        assert_eq!(narinfo.url, format!("nar/{}.nar.zst", path.hash()));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::{
+        Metadata, Permissions, read_dir, remove_dir_all, rename, set_permissions, symlink_metadata,
+    },
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use filetime::{FileTime, set_symlink_file_times};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+// the same as what Nix uses for everything in /nix/store
+const MTIME: FileTime = FileTime::from_unix_time(1, 0);
+
+// make everything in the given path read-only, and set modification times to 1,
+// only touching metadata that differs so hard linked files are left alone
+pub fn canonicalise(path: &Utf8Path) -> Result<()> {
+    let mut paths = vec![path.to_owned()];
+
+    while let Some(path) = paths.pop() {
+        let metadata = symlink_metadata(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read {path}"))?;
+
+        if metadata.is_dir() {
+            for entry in read_dir(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to read {path}"))?
+            {
+                let entry = entry.into_diagnostic()?;
+                paths.push(Utf8PathBuf::try_from(entry.path()).into_diagnostic()?);
+            }
+        }
+
+        if !metadata.is_symlink() {
+            let mode = canonical_mode(&metadata);
+            if metadata.mode() & 0o7777 != mode {
+                set_permissions(&path, Permissions::from_mode(mode))
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("failed to set permissions of {path}"))?;
+            }
+        }
+
+        if FileTime::from_last_modification_time(&metadata) != MTIME {
+            set_symlink_file_times(&path, MTIME, MTIME)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to set modification time of {path}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+// whether canonicalise would leave the metadata of a single file alone
+pub fn is_canonical(metadata: &Metadata) -> bool {
+    (metadata.is_symlink() || metadata.mode() & 0o7777 == canonical_mode(metadata))
+        && FileTime::from_last_modification_time(metadata) == MTIME
+}
+
+fn canonical_mode(metadata: &Metadata) -> u32 {
+    if metadata.is_dir() || metadata.mode() & 0o111 != 0 {
+        0o555
+    } else {
+        0o444
+    }
+}
+
+// canonicalise a temporary path before moving it into the store like Nix,
+// so a failure never leaves anything writable in the store
+pub fn canonicalise_into(tmp: &Utf8Path, out: &Utf8Path) -> Result<()> {
+    canonicalise(tmp)?;
+    if !symlink_metadata(tmp).into_diagnostic()?.is_dir() {
+        return rename(tmp, out)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to move {tmp} to {out}"));
+    }
+
+    // moving a directory to a different parent requires write permission on the directory
+    set_permissions(tmp, Permissions::from_mode(0o755))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to set permissions of {tmp}"))?;
+    rename(tmp, out)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to move {tmp} to {out}"))?;
+
+    let res = set_permissions(out, Permissions::from_mode(0o555))
+        .and_then(|()| set_symlink_file_times(out, MTIME, MTIME));
+    if let Err(e) = res {
+        let _ = make_writable(out.as_std_path()).and_then(|()| remove_dir_all(out));
+        return Err(e)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to canonicalise {out}"));
+    }
+    Ok(())
+}
+
+// replace a file in a read-only directory, restoring the permissions and mtime of the directory
+pub fn replace_file(
+    path: &Utf8Path,
+    replace: impl FnOnce(&Utf8Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let Some(parent) = path.parent() else {
+        return replace(path);
+    };
+
+    let metadata = symlink_metadata(parent)?;
+    let permissions = metadata.permissions();
+    if permissions.mode() & 0o200 != 0 {
+        return replace(path);
+    }
+
+    set_permissions(parent, Permissions::from_mode(permissions.mode() | 0o200))?;
+    let res = replace(path);
+    set_permissions(parent, permissions)?;
+    set_symlink_file_times(
+        parent,
+        FileTime::from_last_access_time(&metadata),
+        FileTime::from_last_modification_time(&metadata),
+    )?;
+    res
+}
+
+// make all directories writable so the tree can be deleted
+pub fn make_writable(path: &Path) -> io::Result<()> {
+    let mut paths = vec![path.to_owned()];
+
+    while let Some(path) = paths.pop() {
+        let metadata = symlink_metadata(&path)?;
+        if !metadata.is_dir() {
+            continue;
+        }
+
+        let mode = metadata.mode();
+        if mode & 0o700 != 0o700 {
+            set_permissions(&path, Permissions::from_mode(mode | 0o700))?;
+        }
+        for entry in read_dir(&path)? {
+            paths.push(entry?.path());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use camino::Utf8Path;
+use harmonia_utils_hash::{
+    Algorithm, Hash,
+    fmt::{Any, CommonHash},
+};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::task::spawn_blocking;
+use tracing::info;
+use url::Url;
+
+use crate::store::{Store, hash_nar, path::StorePath};
+
+// modelled after the ValidPaths and Refs tables of the Nix database,
+// except references are not required to be valid,
+// since unnix registers store paths in an arbitrary order
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS ValidPaths (
+    id               INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    path             TEXT UNIQUE NOT NULL,
+    hash             TEXT NOT NULL,
+    narSize          INTEGER NOT NULL,
+    deriver          TEXT,
+    sigs             TEXT,
+    cache            TEXT,
+    registrationTime INTEGER NOT NULL,
+    lastAccessTime   INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS Refs (
+    referrer  INTEGER NOT NULL,
+    reference TEXT NOT NULL,
+    PRIMARY KEY (referrer, reference),
+    FOREIGN KEY (referrer) REFERENCES ValidPaths(id) ON DELETE CASCADE
+);
+
+-- narinfos from binary caches, where narinfo is NULL if the cache does not have the store path
+CREATE TABLE IF NOT EXISTS NarInfos (
+    cache     TEXT NOT NULL,
+    hashPart  TEXT NOT NULL,
+    narinfo   TEXT,
+    timestamp INTEGER NOT NULL,
+    PRIMARY KEY (cache, hashPart)
+);
+";
+
+// same as the narinfo-cache-positive-ttl and narinfo-cache-negative-ttl Nix settings
+const POSITIVE_TTL: i64 = 30 * 24 * 60 * 60;
+const NEGATIVE_TTL: i64 = 60 * 60;
+
+#[derive(Debug)]
+pub struct PathInfo {
+    pub nar_hash: Hash,
+    pub nar_size: u64,
+    pub references: Vec<StorePath>,
+    pub deriver: Option<String>,
+    pub sigs: Vec<String>,
+    pub cache: Option<String>,
+}
+
+struct Row {
+    hash: String,
+    nar_size: i64,
+    deriver: Option<String>,
+    sigs: Option<String>,
+    cache: Option<String>,
+}
+
+pub fn open(path: &Utf8Path) -> Result<Connection> {
+    let db = Connection::open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to open {path}"))?;
+    db.pragma_update(None, "journal_mode", "WAL")
+        .into_diagnostic()?;
+    db.pragma_update(None, "foreign_keys", true)
+        .into_diagnostic()?;
+    // multiple unnix instances can be writing to the database at the same time
+    db.busy_timeout(Duration::from_secs(60)).into_diagnostic()?;
+    db.execute_batch(SCHEMA)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to initialize {path}"))?;
+    Ok(db)
+}
+
+// stores from before the database kept references in references/<hash>,
+// so store paths that have them are registered with the nar hashes of their contents
+pub fn import_references(db: &mut Connection, dir: &Utf8Path, store: &Utf8Path) -> Result<()> {
+    let mut paths = HashMap::new();
+    for entry in read_dir(store).into_diagnostic()? {
+        if let Ok(name) = entry.into_diagnostic()?.file_name().into_string()
+            && let Ok(path) = StorePath::from_storeless(name)
+        {
+            paths.insert(path.hash().to_owned(), path);
+        }
+    }
+
+    let mut imported = 0;
+    let tx = db.transaction().into_diagnostic()?;
+    for entry in read_dir(dir).into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        let Some(path) = entry.file_name().to_str().and_then(|hash| paths.get(hash)) else {
+            continue;
+        };
+
+        let references = read_to_string(entry.path()).into_diagnostic()?;
+        let (nar_hash, nar_size) = hash_nar(&store.join(path), Algorithm::SHA256)?;
+        let now = now();
+        tx.execute(
+            "INSERT OR IGNORE INTO ValidPaths
+            (path, hash, narSize, registrationTime, lastAccessTime)
+            VALUES (?1, ?2, ?3, ?4, ?4)",
+            params![
+                path.as_str(),
+                nar_hash.as_base32().to_string(),
+                nar_size as i64,
+                now,
+            ],
+        )
+        .into_diagnostic()?;
+        if tx.changes() == 0 {
+            continue;
+        }
+        let id = tx.last_insert_rowid();
+        for reference in references.lines().filter(|line| !line.is_empty()) {
+            let reference = StorePath::from_storeless(reference)?;
+            tx.execute(
+                "INSERT OR IGNORE INTO Refs (referrer, reference) VALUES (?1, ?2)",
+                params![id, reference.as_str()],
+            )
+            .into_diagnostic()?;
+        }
+        imported += 1;
+    }
+    tx.commit().into_diagnostic()?;
+
+    info!("imported {imported} store paths from {dir}");
+    Ok(())
+}
+
+impl Store {
+    async fn with_db<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let db = self.db.clone();
+        spawn_blocking(move || f(&mut db.lock().unwrap()))
+            .await
+            .into_diagnostic()?
+            .into_diagnostic()
+    }
+
+    // registration and last access times are set to the current time
+    pub async fn register(&self, path: &StorePath, info: &PathInfo) -> Result<()> {
+        let name = path.as_str().to_owned();
+        let hash = info.nar_hash.as_base32().to_string();
+        let nar_size = info.nar_size as i64;
+        let references: Vec<_> = info
+            .references
+            .iter()
+            .map(|path| path.to_string())
+            .collect();
+        let deriver = info.deriver.clone();
+        let sigs = info.sigs.join(" ");
+        let cache = info.cache.clone();
+        let now = now();
+
+        self.with_db(move |db| {
+            let tx = db.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO ValidPaths
+                (path, hash, narSize, deriver, sigs, cache, registrationTime, lastAccessTime)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+                params![name, hash, nar_size, deriver, sigs, cache, now],
+            )?;
+            let id = tx.last_insert_rowid();
+            let mut stmt = tx.prepare("INSERT INTO Refs (referrer, reference) VALUES (?1, ?2)")?;
+            for reference in references {
+                stmt.execute(params![id, reference])?;
+            }
+            drop(stmt);
+            tx.commit()
+        })
+        .await
+        .wrap_err_with(|| format!("failed to register {path}"))
+    }
+
+    pub async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        let name = path.as_str().to_owned();
+        let res = self
+            .with_db(move |db| {
+                let Some((id, row)) = db
+                    .query_row(
+                        "SELECT id, hash, narSize, deriver, sigs, cache
+                        FROM ValidPaths WHERE path = ?1",
+                        [&name],
+                        |row| {
+                            Ok((
+                                row.get::<_, i64>(0)?,
+                                Row {
+                                    hash: row.get(1)?,
+                                    nar_size: row.get(2)?,
+                                    deriver: row.get(3)?,
+                                    sigs: row.get(4)?,
+                                    cache: row.get(5)?,
+                                },
+                            ))
+                        },
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                let references = db
+                    .prepare("SELECT reference FROM Refs WHERE referrer = ?1")?
+                    .query_map([id], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(Some((row, references)))
+            })
+            .await?;
+
+        let Some((row, references)) = res else {
+            return Ok(None);
+        };
+
+        Ok(Some(PathInfo {
+            nar_hash: row
+                .hash
+                .parse::<Any<_>>()
+                .into_diagnostic()
+                .wrap_err_with(|| format!("invalid hash for {path} in the store database"))?
+                .into(),
+            nar_size: row.nar_size as u64,
+            references: references
+                .into_iter()
+                .map(StorePath::from_storeless)
+                .collect::<Result<_>>()?,
+            deriver: row.deriver,
+            sigs: row
+                .sigs
+                .iter()
+                .flat_map(|sigs| sigs.split_whitespace())
+                .map(ToOwned::to_owned)
+                .collect(),
+            cache: row.cache,
+        }))
+    }
+
+    // returns None if the store path is not valid
+    pub async fn get_references(&self, path: &StorePath) -> Result<Option<Vec<StorePath>>> {
+        Ok(self
+            .query_path_info(path)
+            .await?
+            .map(|info| info.references))
+    }
+
+    // look up a valid store path by its hash,
+    // with a range instead of LIKE so the index on path is used
+    pub async fn find(&self, hash: &str) -> Result<Option<StorePath>> {
+        let start = format!("{hash}-");
+        let end = format!("{hash}.");
+        self.with_db(move |db| {
+            db.query_row(
+                "SELECT path FROM ValidPaths WHERE path >= ?1 AND path < ?2 LIMIT 1",
+                [start, end],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+        })
+        .await?
+        .map(StorePath::from_storeless)
+        .transpose()
+    }
+
+    // updated in one transaction, since whole closures are touched every time they are used
+    pub async fn touch(&self, paths: Vec<StorePath>) -> Result<()> {
+        let now = now();
+        self.with_db(move |db| {
+            let tx = db.transaction()?;
+            let mut stmt =
+                tx.prepare("UPDATE ValidPaths SET lastAccessTime = ?1 WHERE path = ?2")?;
+            for path in paths {
+                stmt.execute(params![now, path.as_str()])?;
+            }
+            drop(stmt);
+            tx.commit()
+        })
+        .await
+    }
+
+    pub async fn invalidate(&self, path: &StorePath) -> Result<()> {
+        let path = path.as_str().to_owned();
+        self.with_db(move |db| db.execute("DELETE FROM ValidPaths WHERE path = ?1", [path]))
+            .await?;
+        Ok(())
+    }
+
+    // Some(None) if the cache is known to not have the store path
+    pub async fn query_narinfo(&self, cache: &Url, hash: &str) -> Result<Option<Option<String>>> {
+        let cache = cache.to_string();
+        let hash = hash.to_owned();
+        let now = now();
+        self.with_db(move |db| {
+            db.query_row(
+                "SELECT narinfo FROM NarInfos
+                WHERE cache = ?1 AND hashPart = ?2
+                AND timestamp >= ?3 - IIF(narinfo IS NULL, ?4, ?5)",
+                params![cache, hash, now, NEGATIVE_TTL, POSITIVE_TTL],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+        .wrap_err("failed to query the narinfo cache")
+    }
+
+    // narinfos should only be cached after they are verified
+    pub async fn cache_narinfo(
+        &self,
+        cache: &Url,
+        hash: &str,
+        narinfo: Option<&str>,
+    ) -> Result<()> {
+        let cache = cache.to_string();
+        let hash = hash.to_owned();
+        let narinfo = narinfo.map(ToOwned::to_owned);
+        let now = now();
+        self.with_db(move |db| {
+            db.execute(
+                "INSERT OR REPLACE INTO NarInfos (cache, hashPart, narinfo, timestamp)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![cache, hash, narinfo, now],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn expire_narinfos(&self) -> Result<()> {
+        let now = now();
+        self.with_db(move |db| {
+            db.execute(
+                "DELETE FROM NarInfos
+                WHERE timestamp < ?1 - IIF(narinfo IS NULL, ?2, ?3)",
+                params![now, NEGATIVE_TTL, POSITIVE_TTL],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn valid_paths(&self) -> Result<Vec<StorePath>> {
+        self.with_db(|db| {
+            db.prepare("SELECT path FROM ValidPaths")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await?
+        .into_iter()
+        .map(StorePath::from_storeless)
+        .collect()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
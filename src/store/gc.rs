@@ -0,0 +1,167 @@
+use std::{
+    collections::BTreeSet,
+    fs::Permissions,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use camino::Utf8PathBuf;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use tokio::{
+    fs::{read_dir, read_link, remove_file, rename, set_permissions, symlink_metadata},
+    task::spawn_blocking,
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::{
+    lockfile::Lockfile,
+    store::{Store, canonicalise::make_writable, lock_file, path::StorePath},
+};
+
+impl Store {
+    pub async fn gc(&self) -> Result<()> {
+        let _lock = lock_file(&self.gc_lock, false).await?;
+
+        let mut paths = self.roots().await?;
+        let mut live = BTreeSet::new();
+        while let Some(path) = paths.pop() {
+            if live.contains(&path) {
+                continue;
+            }
+            if let Some(references) = self.get_references(&path).await? {
+                paths.extend(references);
+            }
+            live.insert(path);
+        }
+
+        let mut deleted = 0;
+        let mut freed = 0;
+        let mut entries = read_dir(&self.path).await.into_diagnostic()?;
+        while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+            if let Ok(name) = entry.file_name().into_string()
+                && let Ok(path) = StorePath::from_storeless(name)
+            {
+                if live.contains(&path) {
+                    continue;
+                }
+                if let Some(info) = self.query_path_info(&path).await? {
+                    freed += info.nar_size;
+                }
+            }
+
+            debug!("deleting {}", entry.path().display());
+            self.remove_entry(&entry.path()).await?;
+            deleted += 1;
+        }
+
+        // forget about store paths that are no longer in the store
+        for path in self.valid_paths().await? {
+            if !live.contains(&path) || symlink_metadata(self.path.join(&path)).await.is_err() {
+                self.invalidate(&path).await?;
+            }
+        }
+
+        self.expire_narinfos().await?;
+
+        // files only linked from .links are no longer used by any store path
+        let mut entries = read_dir(&self.links).await.into_diagnostic()?;
+        while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+            if entry.metadata().await.into_diagnostic()?.nlink() == 1 {
+                remove_path(&entry.path()).await?;
+            }
+        }
+
+        // nothing can be holding these locks while the gc lock is held exclusively
+        let mut entries = read_dir(&self.lock).await.into_diagnostic()?;
+        while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+            if symlink_metadata(self.path.as_std_path().join(entry.file_name()))
+                .await
+                .is_err()
+            {
+                remove_path(&entry.path()).await?;
+            }
+        }
+
+        let mut entries = read_dir(self.tmp.as_ref()).await.into_diagnostic()?;
+        while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+            remove_path(&entry.path()).await?;
+        }
+
+        info!(
+            "deleted {deleted} store paths, freeing {:.2} MiB",
+            freed as f64 / (1024 * 1024) as f64,
+        );
+        Ok(())
+    }
+
+    async fn roots(&self) -> Result<Vec<StorePath>> {
+        let mut paths = Vec::new();
+        let mut entries = read_dir(&self.gcroots).await.into_diagnostic()?;
+
+        while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+            let root = entry.path();
+            let target = read_link(&root)
+                .await
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to read {}", root.display()))?;
+            let target = Utf8PathBuf::try_from(target).into_diagnostic()?;
+
+            let Some(dir) = target.parent() else {
+                continue;
+            };
+            if symlink_metadata(&target).await.is_err() {
+                debug!("removing stale root for {target}");
+                remove_file(&root).await.into_diagnostic()?;
+                continue;
+            }
+
+            // paths of a root that can't be read can't be told apart from garbage,
+            // so garbage collection stops instead of deleting them
+            let lockfile = Lockfile::from_dir(dir).wrap_err_with(|| {
+                format!(
+                    "failed to read garbage collection root {target}, fix or remove it to continue"
+                )
+            })?;
+            for system in lockfile.systems.keys() {
+                paths.extend(lockfile.collect_outputs(system));
+            }
+        }
+
+        Ok(paths)
+    }
+
+    // move the entry out of the store first so it never appears half-deleted
+    pub(super) async fn remove_entry(&self, path: &Path) -> Result<()> {
+        // moving a directory to a different parent requires write permission on the directory
+        let metadata = symlink_metadata(path).await.into_diagnostic()?;
+        if metadata.is_dir() {
+            let mode = metadata.permissions().mode();
+            set_permissions(path, Permissions::from_mode(mode | 0o700))
+                .await
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to set permissions of {}", path.display()))?;
+        }
+
+        let tmp = self.tmp.join(Uuid::new_v4().simple().to_string());
+        rename(path, &tmp)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to move {} to {tmp}", path.display()))?;
+        remove_path(tmp.as_std_path()).await
+    }
+}
+
+async fn remove_path(path: &Path) -> Result<()> {
+    let res = if symlink_metadata(path).await.into_diagnostic()?.is_dir() {
+        // store entries are read-only
+        let path = path.to_owned();
+        spawn_blocking(move || make_writable(&path).and_then(|()| std::fs::remove_dir_all(path)))
+            .await
+            .into_diagnostic()?
+    } else {
+        remove_file(path).await
+    };
+    res.into_diagnostic()
+        .wrap_err_with(|| format!("failed to delete {}", path.display()))
+}
@@ -0,0 +1,320 @@
+use std::{
+    fs::{copy, create_dir, hard_link, read_dir, read_link, symlink_metadata},
+    io,
+    os::unix::fs::symlink,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use harmonia_utils_hash::fmt::{Any, CommonHash};
+use miette::{IntoDiagnostic, Result, WrapErr, bail, miette};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use tempfile::TempDir;
+use tokio::task::spawn_blocking;
+use tracing::debug;
+
+use crate::store::{
+    PathInfo, Store,
+    canonicalise::{canonicalise_into, is_canonical},
+    hash_nar,
+    path::StorePath,
+};
+
+const HOST_DB: &str = "/nix/var/nix/db/db.sqlite";
+
+// the Nix store of the host, if there is one
+#[derive(Clone)]
+pub struct HostStore {
+    db: Arc<Mutex<Connection>>,
+    dir: Arc<Utf8Path>,
+}
+
+impl HostStore {
+    pub fn open() -> Option<Self> {
+        Self::open_at(HOST_DB.into(), "/nix/store".into())
+    }
+
+    fn open_at(path: &Utf8Path, dir: &Utf8Path) -> Option<Self> {
+        if !path.exists() {
+            return None;
+        }
+
+        let res =
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).and_then(|db| {
+                db.prepare("SELECT 1 FROM ValidPaths LIMIT 1")?;
+                Ok(db)
+            });
+        match res {
+            Ok(db) => Some(Self {
+                db: Arc::new(Mutex::new(db)),
+                dir: dir.into(),
+            }),
+            Err(e) => {
+                debug!("failed to open {path}: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        let name = format!("/nix/store/{path}");
+        let db = self.db.clone();
+        let res = spawn_blocking(move || -> rusqlite::Result<_> {
+            let db = db.lock().unwrap();
+            let Some((id, hash, nar_size, deriver, sigs)) = db
+                .query_row(
+                    "SELECT id, hash, narSize, deriver, sigs FROM ValidPaths WHERE path = ?1",
+                    [&name],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, Option<i64>>(2)?,
+                            row.get::<_, Option<String>>(3)?,
+                            row.get::<_, Option<String>>(4)?,
+                        ))
+                    },
+                )
+                .optional()?
+            else {
+                return Ok(None);
+            };
+
+            let references = db
+                .prepare(
+                    "SELECT path FROM Refs JOIN ValidPaths ON reference = id WHERE referrer = ?1",
+                )?
+                .query_map([id], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some((hash, nar_size, deriver, sigs, references)))
+        })
+        .await
+        .into_diagnostic()?
+        .into_diagnostic()
+        .wrap_err("failed to query the Nix database")?;
+
+        let Some((hash, Some(nar_size), deriver, sigs, references)) = res else {
+            return Ok(None);
+        };
+        // the path might have been deleted without the database being updated
+        if symlink_metadata(self.dir.join(path)).is_err() {
+            return Ok(None);
+        }
+
+        Ok(Some(PathInfo {
+            nar_hash: hash.parse::<Any<_>>().into_diagnostic()?.into(),
+            nar_size: nar_size as u64,
+            references: references
+                .iter()
+                .map(|path| StorePath::new(path))
+                .collect::<Result<_>>()?,
+            deriver: deriver
+                .and_then(|path| path.strip_prefix("/nix/store/").map(ToOwned::to_owned)),
+            sigs: sigs
+                .iter()
+                .flat_map(|sigs| sigs.split_whitespace())
+                .map(ToOwned::to_owned)
+                .collect(),
+            cache: None,
+        }))
+    }
+}
+
+impl Store {
+    // files that are already canonical are hard linked when possible, and copied otherwise,
+    // which reflinks them on filesystems that support it,
+    // so canonicalising the copy never changes the metadata of a file shared with the host store
+    // host paths are trusted like Nix trusts its own database, so their signatures aren't checked,
+    // but the copy is checked against the nar hash in case the host store was modified
+    pub async fn copy_from_host(
+        &self,
+        host: &HostStore,
+        path: &StorePath,
+        info: &PathInfo,
+    ) -> Result<()> {
+        let src = host.dir.join(path);
+        let store_tmp = self.tmp.clone();
+        let (nar_hash, nar_size) = (info.nar_hash, info.nar_size);
+        let name = path.clone();
+        let tmp = spawn_blocking(move || {
+            let dir = TempDir::new_in(store_tmp.as_ref()).into_diagnostic()?;
+            let tmp = Utf8PathBuf::try_from(dir.path().join("out")).into_diagnostic()?;
+            copy_tree(src.as_std_path(), tmp.as_std_path())
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to copy {src}"))?;
+
+            let (actual_hash, actual_size) = hash_nar(&tmp, nar_hash.algorithm())?;
+            if actual_size != nar_size {
+                bail!(
+                    "nar size mismatch for {name} in /nix/store\nexpected: {nar_size}\n  actual: \
+                     {actual_size}",
+                );
+            }
+            if actual_hash != nar_hash {
+                return Err(miette!(
+                    "expected: {}\n  actual: {}",
+                    nar_hash.sri(),
+                    actual_hash.sri(),
+                )
+                .wrap_err(format!("nar hash mismatch for {name} in /nix/store")));
+            }
+
+            Result::<_>::Ok((dir, tmp))
+        })
+        .await
+        .into_diagnostic()??;
+
+        self.delete(path).await?;
+        let out = self.path.join(path);
+        spawn_blocking(move || {
+            let (_dir, tmp) = tmp;
+            canonicalise_into(&tmp, &out)
+        })
+        .await
+        .into_diagnostic()??;
+
+        self.register(path, info).await
+    }
+}
+
+fn copy_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut paths = vec![(src.to_owned(), dst.to_owned())];
+
+    while let Some((src, dst)) = paths.pop() {
+        let metadata = symlink_metadata(&src)?;
+        if metadata.is_dir() {
+            create_dir(&dst)?;
+            for entry in read_dir(&src)? {
+                let entry = entry?;
+                paths.push((entry.path(), dst.join(entry.file_name())));
+            }
+        } else if metadata.is_symlink() {
+            symlink(read_link(&src)?, &dst)?;
+        } else if !is_canonical(&metadata) || hard_link(&src, &dst).is_err() {
+            copy(&src, &dst)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{Permissions, create_dir, read_to_string, set_permissions, symlink_metadata, write},
+        io::Read,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+
+    use camino::Utf8Path;
+    use filetime::{FileTime, set_file_mtime};
+    use harmonia_utils_hash::{Algorithm, fmt::CommonHash};
+    use nix_nar::Encoder;
+    use rusqlite::{Connection, params};
+    use tempfile::TempDir;
+
+    use super::HostStore;
+    use crate::store::{Store, path::StorePath};
+
+    #[tokio::test]
+    async fn copy_from_host() {
+        let tmp = TempDir::new().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let store = Store::new(&root.join("root")).unwrap();
+
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let glibc =
+            StorePath::from_storeless("5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66").unwrap();
+        let dir = root.join("nix/store");
+        create_dir(root.join("nix")).unwrap();
+        create_dir(&dir).unwrap();
+        create_dir(dir.join(&path)).unwrap();
+        write(dir.join(&path).join("hello"), "world").unwrap();
+        // canonical like the files of a real Nix store
+        let linked = dir.join(&path).join("linked");
+        write(&linked, "").unwrap();
+        set_permissions(&linked, Permissions::from_mode(0o444)).unwrap();
+        set_file_mtime(&linked, FileTime::from_unix_time(1, 0)).unwrap();
+        create_dir(dir.join(&glibc)).unwrap();
+
+        let mut nar = Vec::new();
+        Encoder::new(dir.join(&path))
+            .unwrap()
+            .read_to_end(&mut nar)
+            .unwrap();
+        let nar_hash = Algorithm::SHA256.digest(&nar);
+        let nar_size = nar.len() as u64;
+
+        let db = Connection::open(root.join("db.sqlite")).unwrap();
+        db.execute_batch(
+            "CREATE TABLE ValidPaths (
+                id INTEGER PRIMARY KEY, path TEXT, hash TEXT, narSize INTEGER,
+                deriver TEXT, sigs TEXT
+            );
+            CREATE TABLE Refs (referrer INTEGER, reference INTEGER);",
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO ValidPaths VALUES (1, ?1, ?2, ?3, ?4, NULL)",
+            params![
+                format!("/nix/store/{path}"),
+                nar_hash.as_base16().to_string(),
+                nar_size as i64,
+                "/nix/store/gciipqhqkdlqqn803zd4a389v86ran45-hello-2.12.1.drv",
+            ],
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO ValidPaths VALUES (2, ?1, ?2, 0, NULL, NULL)",
+            params![
+                format!("/nix/store/{glibc}"),
+                nar_hash.as_base16().to_string()
+            ],
+        )
+        .unwrap();
+        db.execute("INSERT INTO Refs VALUES (1, 2)", []).unwrap();
+        drop(db);
+
+        let host = HostStore::open_at(&root.join("db.sqlite"), &dir).unwrap();
+        let missing =
+            StorePath::from_storeless("00000000000000000000000000000000-missing").unwrap();
+        assert!(host.query_path_info(&missing).await.unwrap().is_none());
+
+        let info = host.query_path_info(&path).await.unwrap().unwrap();
+        assert_eq!(info.nar_hash, nar_hash);
+        assert_eq!(info.references, [glibc]);
+        assert_eq!(
+            info.deriver.as_deref(),
+            Some("gciipqhqkdlqqn803zd4a389v86ran45-hello-2.12.1.drv"),
+        );
+
+        // the host store was modified after the path was registered
+        write(dir.join(&path).join("hello"), "tampered").unwrap();
+        assert!(store.copy_from_host(&host, &path, &info).await.is_err());
+        assert!(store.path.join(&path).symlink_metadata().is_err());
+        write(dir.join(&path).join("hello"), "world").unwrap();
+
+        store.copy_from_host(&host, &path, &info).await.unwrap();
+        assert_eq!(
+            read_to_string(store.path.join(&path).join("hello")).unwrap(),
+            "world",
+        );
+
+        // files that aren't canonical are copied instead of being canonicalised in the host store
+        let host_file = symlink_metadata(dir.join(&path).join("hello")).unwrap();
+        let file = symlink_metadata(store.path.join(&path).join("hello")).unwrap();
+        assert_ne!(host_file.ino(), file.ino());
+        assert_eq!(host_file.mode() & 0o7777, 0o644);
+        assert_eq!(file.mode() & 0o7777, 0o444);
+        let host_file = symlink_metadata(&linked).unwrap();
+        let file = symlink_metadata(store.path.join(&path).join("linked")).unwrap();
+        assert_eq!(host_file.ino(), file.ino());
+        assert_eq!(
+            store.nar_hash(&path, Algorithm::SHA256).await.unwrap(),
+            (nar_hash, nar_size),
+        );
+        assert!(store.query_path_info(&path).await.unwrap().is_some());
+    }
+}
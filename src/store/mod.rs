@@ -1,15 +1,19 @@
+mod canonicalise;
+mod db;
+mod gc;
+mod host;
 pub mod nar;
+mod optimise;
 pub mod path;
 
 use std::{
     collections::BTreeSet,
     env::{VarError, var},
-    fmt::Write,
     fs::create_dir_all,
-    io::Cursor,
+    io::{self, Read},
     num::NonZero,
     pin::pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -20,202 +24,286 @@ use async_stream::stream;
 use camino::{Utf8Path, Utf8PathBuf};
 use dirs::cache_dir;
 use fs4::{TryLockError, tokio::AsyncFileExt};
-use harmonia_utils_hash::{Hash, fmt::CommonHash};
+use harmonia_store_core::store_path::ContentAddress;
+use harmonia_utils_hash::{Algorithm, Context, Hash, fmt::CommonHash};
 use miette::{IntoDiagnostic, Report, Result, WrapErr, bail, miette};
-use nix_nar::Decoder;
-use tempfile::{NamedTempFile, TempDir};
+use nix_nar::{Decoder, Encoder};
+use rusqlite::Connection;
+use tempfile::TempDir;
 use tokio::{
-    fs::{File, read_dir, rename, symlink_metadata},
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::{File, read_dir, read_link, rename, symlink, symlink_metadata},
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt},
     task::{JoinSet, LocalSet, spawn_blocking},
     time::sleep,
 };
-use tokio_stream::{Stream, StreamExt, wrappers::LinesStream};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::SyncIoBridge;
+use tracing::{debug, warn};
+use uuid::Uuid;
 
-use crate::store::{nar::Compression, path::StorePath};
+use crate::store::{
+    canonicalise::canonicalise_into, nar::Compression, optimise::optimise_path, path::StorePath,
+};
+pub use crate::store::{db::PathInfo, host::HostStore};
 
 #[derive(Clone)]
 pub struct Store {
     pub path: Utf8PathBuf,
+    pub auto_optimise: bool,
+    gc_lock: Utf8PathBuf,
+    gcroots: Utf8PathBuf,
+    links: Utf8PathBuf,
     lock: Utf8PathBuf,
-    references: Utf8PathBuf,
+    db: Arc<Mutex<Connection>>,
+    pub host: Option<HostStore>,
     tmp: Arc<Utf8Path>,
 }
 
 impl Store {
-    pub fn new() -> Result<Self> {
+    pub fn default_root() -> Result<Utf8PathBuf> {
         let cache = cache_dir().ok_or_else(|| miette!("no cache directory found"))?;
-        let cache = Utf8PathBuf::try_from(cache)
+        Ok(Utf8PathBuf::try_from(cache)
             .into_diagnostic()?
-            .join("unnix");
+            .join("unnix"))
+    }
 
-        let path = cache.join("store");
-        let lock = cache.join("lock");
-        let references = cache.join("references");
-        let tmp = cache.join("tmp");
+    pub fn new(root: &Utf8Path) -> Result<Self> {
+        create_dir_all(root)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to create {root}"))?;
+        let root = root
+            .canonicalize_utf8()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to canonicalize {root}"))?;
 
-        for path in [&path, &lock, &references, &tmp] {
+        let path = root.join("store");
+        let gc_lock = root.join("gc.lock");
+        let gcroots = root.join("gcroots");
+        let links = root.join(".links");
+        let lock = root.join("lock");
+        let tmp = root.join("tmp");
+
+        for path in [&path, &gcroots, &links, &lock, &tmp] {
             create_dir_all(path)
                 .into_diagnostic()
                 .wrap_err_with(|| format!("failed to create {path}"))?;
         }
 
+        let mut db = db::open(&root.join("db.sqlite"))?;
+
+        // only one instance can move references/ away, and it is deleted once it is imported
+        let references = root.join("references");
+        let imported = tmp.join(Uuid::new_v4().simple().to_string());
+        if std::fs::rename(&references, &imported).is_ok() {
+            db::import_references(&mut db, &imported, &path)
+                .wrap_err_with(|| format!("failed to import {references}"))?;
+            std::fs::remove_dir_all(&imported)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to delete {imported}"))?;
+        }
+
         Ok(Store {
             path,
+            auto_optimise: false,
+            gc_lock,
+            gcroots,
+            links,
             lock,
-            references,
+            db: Arc::new(Mutex::new(db)),
+            host: HostStore::open(),
             tmp: tmp.into(),
         })
     }
 
     pub async fn lock_path(&self, path: &StorePath) -> Result<File> {
-        let path = self.lock.join(path);
-        let lock = File::create(&path)
-            .await
+        lock_file(&self.lock.join(path), false).await
+    }
+
+    // held by anything that adds to the store, so garbage collection can't happen concurrently
+    pub async fn lock_gc_shared(&self) -> Result<File> {
+        lock_file(&self.gc_lock, true).await
+    }
+
+    // the caller has to hold the gc lock, since garbage collection reads the roots and clears tmp
+    pub async fn add_root(&self, lockfile: &Utf8Path) -> Result<()> {
+        let lockfile = lockfile
+            .canonicalize_utf8()
             .into_diagnostic()
-            .wrap_err_with(|| format!("failed to create {path}"))?;
-        loop {
-            match lock.try_lock() {
-                Ok(()) => {
-                    return Ok(lock);
-                }
-                Err(TryLockError::WouldBlock) => {
-                    sleep(Duration::from_millis(250)).await;
-                }
-                Err(e) => {
-                    return Err(
-                        Report::from_err(e).wrap_err(format!("failed to acquire lock for {path}"))
-                    );
-                }
-            }
+            .wrap_err_with(|| format!("failed to canonicalize {lockfile}"))?;
+        let root = self
+            .gcroots
+            .join(blake3::hash(lockfile.as_str().as_bytes()).to_hex().as_str());
+
+        if read_link(&root)
+            .await
+            .is_ok_and(|target| target == lockfile)
+        {
+            return Ok(());
         }
+
+        let tmp = self.tmp.join(Uuid::new_v4().simple().to_string());
+        symlink(&lockfile, &tmp)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to create {tmp}"))?;
+        rename(&tmp, &root)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to create {root}"))
     }
 
+    // any existing store entry is only replaced after the nar is verified
     pub async fn unpack_nar(
         &self,
         path: &StorePath,
-        mut reader: impl AsyncBufRead + Unpin,
+        reader: impl AsyncBufRead + Send + Unpin + 'static,
         compression: Compression,
         nar_hash: Hash,
         nar_size: usize,
     ) -> Result<()> {
-        let out = self.path.join(path);
-        if out.symlink_metadata().is_ok() {
-            return Ok(());
+        // the decoders read until the end of the compressed file instead of stopping after the first
+        // frame, so the whole file goes through the FileHash and FileSize check before unpacking ends
+        macro_rules! decoder {
+            ($decoder:expr) => {{
+                let mut decoder = $decoder;
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }};
         }
-
-        let mut buf = Vec::with_capacity(nar_size);
-        match compression {
-            Compression::Brotli => {
-                BrotliDecoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::Bzip2 => {
-                BzDecoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::Gzip => {
-                GzipDecoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::Lz4 => {
-                Lz4Decoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::Lzma => {
-                LzmaDecoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::None => {
-                reader.read_to_end(&mut buf).await.into_diagnostic()?;
-            }
-            Compression::Xz => {
-                XzDecoder::parallel(reader, NonZero::new(4).unwrap())
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
-            }
-            Compression::Zstd => {
-                ZstdDecoder::new(reader)
-                    .read_to_end(&mut buf)
-                    .await
-                    .into_diagnostic()?;
+        let reader: Box<dyn AsyncRead + Send + Unpin> = match compression {
+            Compression::Brotli => decoder!(BrotliDecoder::new(reader)),
+            Compression::Bzip2 => decoder!(BzDecoder::new(reader)),
+            Compression::Gzip => decoder!(GzipDecoder::new(reader)),
+            Compression::Lz4 => decoder!(Lz4Decoder::new(reader)),
+            Compression::Lzma => decoder!(LzmaDecoder::new(reader)),
+            Compression::None => Box::new(reader),
+            Compression::Xz => decoder!(XzDecoder::parallel(reader, NonZero::new(4).unwrap())),
+            Compression::Zstd => decoder!(ZstdDecoder::new(reader)),
+        };
+        let reader = SyncIoBridge::new(reader);
+
+        let name = path.clone();
+        let store_tmp = self.tmp.clone();
+        let tmp = spawn_blocking(move || {
+            let dir = TempDir::new_in(store_tmp.as_ref()).into_diagnostic()?;
+            let tmp = Utf8PathBuf::try_from(dir.path().join("out")).into_diagnostic()?;
+
+            // the nar is hashed while it is being unpacked,
+            // and only moved into the store after the hash is verified
+            let mut reader = io::BufReader::new(HashReader {
+                inner: reader,
+                hasher: Context::new(nar_hash.algorithm()),
+                size: 0,
+            });
+            Decoder::new(&mut reader)
+                .into_diagnostic()?
+                .unpack(&tmp)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to unpack {name}"))?;
+            io::copy(&mut reader, &mut io::sink()).into_diagnostic()?;
+
+            let reader = reader.into_inner();
+            if reader.size != nar_size as u64 {
+                bail!(
+                    "nar size mismatch for {name}\nexpected: {nar_size}\n  actual: {}",
+                    reader.size,
+                );
             }
-        }
 
-        let path = path.clone();
-        let tmp = self.tmp.clone();
-        spawn_blocking(move || {
-            let actual_hash = nar_hash.algorithm().digest(&buf);
+            let actual_hash = reader.hasher.finish();
             if actual_hash != nar_hash {
                 return Err(miette!(
                     "expected: {}\n  actual: {}",
                     nar_hash.sri(),
                     actual_hash.sri(),
                 )
-                .wrap_err(format!("nar hash mismatch for {path}")));
+                .wrap_err(format!("nar hash mismatch for {name}")));
             }
 
-            let tmp = TempDir::new_in(tmp.as_ref()).into_diagnostic()?;
-            let tmp = tmp.path().join("out");
+            Result::<_>::Ok((dir, tmp))
+        })
+        .await
+        .into_diagnostic()??;
 
-            Decoder::new(Cursor::new(buf))
-                .into_diagnostic()?
-                .unpack(&tmp)
-                .into_diagnostic()?;
+        self.delete(path).await?;
+
+        let name = path.clone();
+        let out = self.path.join(path);
+        let auto_optimise = self.auto_optimise;
+        let links = self.links.clone();
+        let store_tmp = self.tmp.clone();
+        spawn_blocking(move || {
+            let (_dir, tmp) = tmp;
+            canonicalise_into(&tmp, &out)?;
 
-            std::fs::rename(tmp, out).into_diagnostic()
+            if auto_optimise {
+                match optimise_path(&links, &store_tmp, &out) {
+                    Ok(stats) => {
+                        debug!("hard linked {} files in {name}", stats.linked);
+                    }
+                    Err(e) => {
+                        warn!("failed to optimise {name}: {e:?}");
+                    }
+                }
+            }
+
+            Ok(())
         })
         .await
         .into_diagnostic()?
     }
 
-    pub async fn get_references(&self, hash: &str) -> Result<Option<Vec<StorePath>>> {
-        let Ok(file) = File::open(self.references.join(hash)).await else {
-            return Ok(None);
-        };
-
-        LinesStream::new(BufReader::new(file).lines())
-            .map(|line| StorePath::from_storeless(line.into_diagnostic()?))
-            .collect::<Result<_>>()
-            .await
-            .map(Some)
-    }
-
-    pub async fn put_references(&self, hash: &str, references: &[StorePath]) -> Result<()> {
-        let tmp = self.tmp.clone();
-        let tmp = spawn_blocking(move || NamedTempFile::new_in(tmp.as_ref()))
+    pub async fn nar_hash(&self, path: &StorePath, algorithm: Algorithm) -> Result<(Hash, u64)> {
+        let path = self.path.join(path);
+        spawn_blocking(move || hash_nar(&path, algorithm))
             .await
             .into_diagnostic()?
-            .into_diagnostic()?;
+    }
 
-        let (file, path) = tmp.into_parts();
-        let mut file = File::from_std(file);
+    // Nix checks content-addressed store paths the same way when adding them to the store
+    pub async fn verify_ca(&self, path: &StorePath, ca: &ContentAddress) -> Result<()> {
+        let expected = ca.hash();
+        let actual = match ca {
+            ContentAddress::Recursive(_) => self.nar_hash(path, expected.algorithm()).await?.0,
+            ContentAddress::Flat(_) | ContentAddress::Text(_) => {
+                let file = self.path.join(path);
+                spawn_blocking(move || {
+                    let mut reader = HashReader {
+                        inner: std::fs::File::open(&file)
+                            .into_diagnostic()
+                            .wrap_err_with(|| format!("failed to read {file}"))?,
+                        hasher: Context::new(expected.algorithm()),
+                        size: 0,
+                    };
+                    io::copy(&mut reader, &mut io::sink())
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("failed to read {file}"))?;
+                    Result::<_>::Ok(reader.hasher.finish())
+                })
+                .await
+                .into_diagnostic()??
+            }
+        };
 
-        let mut text = String::new();
-        for path in references {
-            writeln!(text, "{path}").into_diagnostic()?;
+        if actual != expected {
+            return Err(
+                miette!("expected: {}\n  actual: {}", expected.sri(), actual.sri(),)
+                    .wrap_err(format!("content address mismatch for {path}")),
+            );
         }
-        file.write_all(text.as_bytes()).await.into_diagnostic()?;
-
-        rename(&path, self.references.join(hash))
-            .await
-            .into_diagnostic()?;
-        let _ = spawn_blocking(|| path.close()).await;
-
         Ok(())
     }
 
+    pub async fn delete(&self, path: &StorePath) -> Result<()> {
+        self.invalidate(path).await?;
+        let path = self.path.join(path);
+        if symlink_metadata(&path).await.is_ok() {
+            self.remove_entry(path.as_std_path()).await
+        } else {
+            Ok(())
+        }
+    }
+
     pub async fn propagated_build_inputs(
         &self,
         mut paths: Vec<StorePath>,
@@ -349,20 +437,220 @@ impl Store {
     }
 }
 
+async fn lock_file(path: &Utf8Path, shared: bool) -> Result<File> {
+    let lock = File::create(path)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to create {path}"))?;
+    loop {
+        let res = if shared {
+            lock.try_lock_shared()
+        } else {
+            lock.try_lock()
+        };
+        match res {
+            Ok(()) => {
+                return Ok(lock);
+            }
+            Err(TryLockError::WouldBlock) => {
+                sleep(Duration::from_millis(250)).await;
+            }
+            Err(e) => {
+                return Err(
+                    Report::from_err(e).wrap_err(format!("failed to acquire lock for {path}"))
+                );
+            }
+        }
+    }
+}
+
+fn hash_nar(path: &Utf8Path, algorithm: Algorithm) -> Result<(Hash, u64)> {
+    let mut reader = HashReader {
+        inner: Encoder::new(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read {path}"))?,
+        hasher: Context::new(algorithm),
+        size: 0,
+    };
+    io::copy(&mut reader, &mut io::sink())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("failed to read {path}"))?;
+    Ok((reader.hasher.finish(), reader.size))
+}
+
+struct HashReader<R> {
+    inner: R,
+    hasher: Context,
+    size: u64,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[.. n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::env::VarError;
-
-    use camino::Utf8PathBuf;
+    use std::{
+        env::VarError,
+        fs::{create_dir, create_dir_all, read_to_string, write},
+        io::{Cursor, Read},
+        os::unix::fs::MetadataExt,
+        sync::{Arc, Mutex},
+    };
+
+    use camino::{Utf8Path, Utf8PathBuf};
+    use harmonia_utils_hash::Algorithm;
+    use nix_nar::Encoder;
+    use rusqlite::Connection;
+    use tempfile::TempDir;
 
     use super::Store;
+    use crate::store::{nar::Compression, path::StorePath};
+
+    #[tokio::test]
+    async fn unpack_nar() {
+        let tmp = TempDir::new().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let store = Store::new(&root.join("root")).unwrap();
+        create_dir(root.join("src")).unwrap();
+        write(root.join("src/hello"), "world").unwrap();
+
+        let mut nar = Vec::new();
+        Encoder::new(root.join("src"))
+            .unwrap()
+            .read_to_end(&mut nar)
+            .unwrap();
+        let nar_hash = Algorithm::SHA256.digest(&nar);
+
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        store
+            .unpack_nar(
+                &path,
+                Cursor::new(nar.clone()),
+                Compression::None,
+                nar_hash,
+                nar.len(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            read_to_string(store.path.join(&path).join("hello")).unwrap(),
+            "world",
+        );
+        assert_eq!(
+            store.nar_hash(&path, Algorithm::SHA256).await.unwrap(),
+            (nar_hash, nar.len() as u64),
+        );
+
+        for (path, mode) in [
+            (store.path.join(&path), 0o555),
+            (store.path.join(&path).join("hello"), 0o444),
+        ] {
+            let metadata = path.symlink_metadata().unwrap();
+            assert_eq!(metadata.mode() & 0o7777, mode);
+            assert_eq!(metadata.mtime(), 1);
+        }
+
+        let path =
+            StorePath::from_storeless("5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66").unwrap();
+        let res = store
+            .unpack_nar(
+                &path,
+                Cursor::new(nar.clone()),
+                Compression::None,
+                Algorithm::SHA256.digest(""),
+                nar.len(),
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(store.path.join(&path).symlink_metadata().is_err());
+
+        // existing entries are only replaced after the nar is verified
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let res = store
+            .unpack_nar(
+                &path,
+                Cursor::new(nar[.. nar.len() - 8].to_vec()),
+                Compression::None,
+                nar_hash,
+                nar.len(),
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(
+            read_to_string(store.path.join(&path).join("hello")).unwrap(),
+            "world",
+        );
+        store
+            .unpack_nar(
+                &path,
+                Cursor::new(nar.clone()),
+                Compression::None,
+                nar_hash,
+                nar.len(),
+            )
+            .await
+            .unwrap();
+
+        store.delete(&path).await.unwrap();
+        assert!(store.path.join(&path).symlink_metadata().is_err());
+    }
+
+    #[tokio::test]
+    async fn import_references() {
+        let tmp = TempDir::new().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let glibc = "5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66";
+        create_dir_all(root.join("store").join(&path)).unwrap();
+        write(root.join("store").join(&path).join("hello"), "world").unwrap();
+        create_dir(root.join("references")).unwrap();
+        write(
+            root.join("references").join(path.hash()),
+            format!("{glibc}\n"),
+        )
+        .unwrap();
+        // the store path was never unpacked
+        write(root.join("references/00000000000000000000000000000000"), "").unwrap();
+
+        let store = Store::new(root).unwrap();
+        assert!(!root.join("references").exists());
+        let info = store.query_path_info(&path).await.unwrap().unwrap();
+        assert_eq!(info.references, [StorePath::from_storeless(glibc).unwrap()]);
+        assert_eq!(
+            store.nar_hash(&path, Algorithm::SHA256).await.unwrap(),
+            (info.nar_hash, info.nar_size),
+        );
+        assert_eq!(store.find(path.hash()).await.unwrap(), Some(path.clone()));
+        assert_eq!(
+            store
+                .find("00000000000000000000000000000000")
+                .await
+                .unwrap(),
+            None,
+        );
+        assert_eq!(store.valid_paths().await.unwrap(), [path]);
+    }
 
     #[tokio::test]
     async fn prefix_env() {
         let store = Store {
             path: "/fake/store".into(),
+            auto_optimise: false,
+            gc_lock: "/dev/null".into(),
+            gcroots: "/dev/null".into(),
+            links: "/dev/null".into(),
             lock: "/dev/null".into(),
-            references: "/dev/null".into(),
+            db: Arc::new(Mutex::new(Connection::open_in_memory().unwrap())),
+            host: None,
             tmp: Utf8PathBuf::from("/dev/null").into(),
         };
 
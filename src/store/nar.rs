@@ -1,21 +1,26 @@
-use std::{fmt::Write, str::FromStr, sync::Arc};
+use std::{fmt::Write, str::FromStr};
 
-use harmonia_store_core::signature::PublicKey;
+use harmonia_store_core::{signature::Signature, store_path::ContentAddress};
 use harmonia_utils_hash::{Hash, fmt::Any};
 use miette::{IntoDiagnostic, Report, Result, WrapErr, bail};
 
-use crate::store::path::StorePath;
+use crate::{cache::Cache, store::path::StorePath};
 
 #[derive(Debug)]
 pub struct Narinfo {
+    pub ca: Option<ContentAddress>,
     pub compression: Compression,
+    pub deriver: Option<String>,
+    pub file_hash: Option<Hash>,
+    pub file_size: Option<usize>,
     pub nar_hash: Hash,
     pub nar_size: usize,
     pub references: Vec<StorePath>,
+    pub sigs: Vec<String>,
     pub url: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Compression {
     Brotli,
     Bzip2,
@@ -28,20 +33,38 @@ pub enum Compression {
 }
 
 impl Narinfo {
-    pub fn parse(content: &str, public_keys: &[Arc<PublicKey>]) -> Result<Self> {
+    // the narinfo has to be for the requested store path,
+    // or a cache could answer with a validly signed or content-addressed narinfo for another path
+    pub fn parse(content: &str, path: &StorePath, cache: &Cache) -> Result<Self> {
+        let mut ca = None;
         let mut compression = None;
+        let mut deriver = None;
+        let mut file_hash = None;
+        let mut file_size = None;
         let mut nar_hash = None;
         let mut nar_size = None;
         let mut references = Vec::new();
-        let mut sig = None;
+        let mut sigs = Vec::new();
         let mut store_path = None;
         let mut url = None;
 
         for (key, value) in content.lines().flat_map(|line| line.split_once(": ")) {
             match key {
+                "CA" => {
+                    ca = Some(value.parse::<ContentAddress>().into_diagnostic()?);
+                }
                 "Compression" => {
                     compression = Some(value.parse()?);
                 }
+                "Deriver" => {
+                    deriver = Some(value.to_owned());
+                }
+                "FileHash" => {
+                    file_hash = Some(value.parse::<Any<Hash>>().into_diagnostic()?.into());
+                }
+                "FileSize" => {
+                    file_size = Some(value.parse().into_diagnostic()?);
+                }
                 "NarHash" => {
                     nar_hash = Some(value);
                 }
@@ -55,7 +78,7 @@ impl Narinfo {
                         .collect::<Result<_>>()?;
                 }
                 "Sig" => {
-                    sig = Some(value.parse().into_diagnostic()?);
+                    sigs.push(value.to_owned());
                 }
                 "StorePath" => {
                     store_path = Some(StorePath::new(value)?);
@@ -70,32 +93,95 @@ impl Narinfo {
         let compression = compression.wrap_err("Compression missing in narinfo")?;
         let nar_hash = nar_hash.wrap_err("NarHash missing in narinfo")?;
         let nar_size = nar_size.wrap_err("NarSize missing in narinfo")?;
-        let sig = sig.wrap_err("Sig missing in narinfo")?;
         let store_path = store_path.wrap_err("StorePath missing in narinfo")?;
         let url = url.wrap_err("URL missing in narinfo")?;
+        if &store_path != path {
+            bail!("narinfo for {path} is for {store_path} instead");
+        }
 
         references.sort();
-        let mut fingerprint = format!("1;/nix/store/{store_path};{nar_hash};{nar_size};");
-        let mut paths = references.iter();
-        if let Some(path) = paths.next() {
-            write!(fingerprint, "/nix/store/{path}").into_diagnostic()?;
-            for path in paths {
-                write!(fingerprint, ",/nix/store/{path}").into_diagnostic()?;
-            }
-        }
+        let fingerprint = fingerprint(&store_path, nar_hash, nar_size, &references)?;
+
+        let parsed = sigs
+            .iter()
+            .map(|sig| sig.parse::<Signature>().into_diagnostic())
+            .collect::<Result<Vec<_>>>()?;
+
+        // like Nix, a signature is only checked against the key with the same name,
+        // and each key counts once no matter how many signatures it has
+        let trusted = cache
+            .public_keys
+            .iter()
+            .filter(|pk| {
+                parsed
+                    .iter()
+                    .any(|sig| sig.name() == pk.name() && pk.verify(&fingerprint, sig))
+            })
+            .count();
 
-        if public_keys.iter().any(|pk| pk.verify(&fingerprint, &sig)) {
+        // content-addressed store paths commit to their contents, so they don't need signatures,
+        // the contents are checked against the content address after they are unpacked
+        let content_addressed = trusted < cache.threshold
+            && ca
+                .as_ref()
+                .is_some_and(|ca| matches_ca(&store_path, ca, &references));
+
+        if trusted >= cache.threshold || content_addressed {
             Ok(Self {
+                ca,
                 compression,
+                deriver,
+                file_hash,
+                file_size,
                 nar_hash: nar_hash.parse::<Any<_>>().into_diagnostic()?.into(),
                 nar_size,
                 references,
+                sigs,
                 url,
             })
+        } else if ca.is_some() {
+            bail!(
+                "{store_path} does not match its content address and is not signed by enough trusted keys"
+            );
+        } else if sigs.is_empty() {
+            bail!("Sig missing in narinfo");
         } else {
-            bail!("failed to verify {store_path}");
+            bail!(
+                "failed to verify {store_path}: {trusted} of {} required signatures are trusted",
+                cache.threshold,
+            );
+        }
+    }
+}
+
+// references of content-addressed store paths can include the store path itself
+fn matches_ca(store_path: &StorePath, ca: &ContentAddress, references: &[StorePath]) -> bool {
+    let self_ref = references.contains(store_path);
+    let references: Vec<_> = references
+        .iter()
+        .filter(|path| *path != store_path)
+        .cloned()
+        .collect();
+    StorePath::from_ca(store_path.name(), ca, &references, self_ref)
+        .is_ok_and(|path| &path == store_path)
+}
+
+// the message signed by binary caches, references must be sorted
+pub fn fingerprint(
+    store_path: &StorePath,
+    nar_hash: &str,
+    nar_size: usize,
+    references: &[StorePath],
+) -> Result<String> {
+    let mut fingerprint = format!("1;/nix/store/{store_path};{nar_hash};{nar_size};");
+    let mut paths = references.iter();
+    if let Some(path) = paths.next() {
+        write!(fingerprint, "/nix/store/{path}").into_diagnostic()?;
+        for path in paths {
+            write!(fingerprint, ",/nix/store/{path}").into_diagnostic()?;
         }
     }
+    Ok(fingerprint)
 }
 
 impl FromStr for Compression {
@@ -120,12 +206,15 @@ impl FromStr for Compression {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{slice, sync::Arc};
 
+    use harmonia_store_core::signature::SecretKey;
     use insta::assert_debug_snapshot;
+    use ring::rand::SystemRandom;
+    use tokio::sync::OnceCell;
 
-    use super::Narinfo;
-    use crate::manifest::DEFAULT_PUBLIC_KEY;
+    use super::{Narinfo, fingerprint};
+    use crate::{cache::Cache, manifest::DEFAULT_PUBLIC_KEY, store::path::StorePath};
 
     #[test]
     fn basic() {
@@ -141,7 +230,109 @@ References: 5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66 hwz2l7ihv2skq7gr5l3pa
 Deriver: gciipqhqkdlqqn803zd4a389v86ran45-hello-2.12.1.drv
 Sig: cache.nixos.org-1:k2IFtC1gRLHfYPqHVmOUI2leueaS6DLXlmiQSsp2tOJ4+kKdx5UAm2m10cR/vz7U50QvgEcvrqCICw2CRLy3Cg==
 ";
-        let pk = Arc::new(DEFAULT_PUBLIC_KEY.parse().unwrap());
-        assert_debug_snapshot!(Narinfo::parse(content, &[pk]));
+        let cache = Cache {
+            url: "https://cache.nixos.org".parse().unwrap(),
+            public_keys: vec![Arc::new(DEFAULT_PUBLIC_KEY.parse().unwrap())],
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new(),
+        };
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        assert_debug_snapshot!(Narinfo::parse(content, &path, &cache));
+    }
+
+    #[test]
+    fn threshold() {
+        let path =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        let nar_hash = "sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw";
+        let fingerprint = fingerprint(&path, nar_hash, 226560, &[]).unwrap();
+
+        let rng = SystemRandom::new();
+        let keys: Vec<_> = ["a-1", "b-1", "c-1"]
+            .into_iter()
+            .map(|name| SecretKey::generate(name.into(), &rng).unwrap())
+            .collect();
+
+        // signed by a and b, but not c
+        let content = format!(
+            "StorePath: /nix/store/{path}\nURL: nar/{path}.nar\nCompression: none\n\
+             NarHash: {nar_hash}\nNarSize: 226560\nReferences: \n\
+             Sig: {}\nSig: {}\n",
+            keys[0].sign(&fingerprint),
+            keys[1].sign(&fingerprint),
+        );
+        let cache = |trusted: &[usize], threshold| Cache {
+            url: "https://example.com".parse().unwrap(),
+            public_keys: trusted
+                .iter()
+                .map(|&i| Arc::new(keys[i].to_public_key()))
+                .collect(),
+            threshold,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new(),
+        };
+
+        let narinfo = Narinfo::parse(&content, &path, &cache(&[0, 1, 2], 2)).unwrap();
+        assert_eq!(narinfo.sigs.len(), 2);
+        // the first signature is checked even if the last one is untrusted
+        assert!(Narinfo::parse(&content, &path, &cache(&[0], 1)).is_ok());
+        assert!(Narinfo::parse(&content, &path, &cache(&[0, 2], 2)).is_err());
+        assert!(Narinfo::parse(&content, &path, &cache(&[0, 1], 3)).is_err());
+        assert!(Narinfo::parse(&content, &path, &cache(&[2], 1)).is_err());
+    }
+
+    #[test]
+    fn ca() {
+        let ca = "fixed:r:sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw";
+        let glibc =
+            StorePath::from_storeless("5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66").unwrap();
+        let path = StorePath::from_ca(
+            "source",
+            &ca.parse().unwrap(),
+            slice::from_ref(&glibc),
+            true,
+        )
+        .unwrap();
+        let cache = Cache {
+            url: "https://example.com".parse().unwrap(),
+            public_keys: vec![Arc::new(DEFAULT_PUBLIC_KEY.parse().unwrap())],
+            threshold: 1,
+            priority: None,
+            want_mass_query: None,
+            token_env: None,
+            info: OnceCell::new(),
+        };
+        let content = |path: &StorePath, ca| {
+            format!(
+                "StorePath: /nix/store/{path}\nURL: nar/{path}.nar\nCompression: none\n\
+                 NarHash: sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw\n\
+                 NarSize: 226560\nReferences: {path} {glibc}\nCA: {ca}\n",
+            )
+        };
+
+        let narinfo = Narinfo::parse(&content(&path, ca), &path, &cache).unwrap();
+        assert_eq!(narinfo.ca.unwrap().to_string(), ca);
+        let other =
+            StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z-hello-2.12.1").unwrap();
+        assert!(Narinfo::parse(&content(&other, ca), &other, &cache).is_err());
+        // the content address is valid, but for a different path than the one requested
+        assert!(Narinfo::parse(&content(&path, ca), &other, &cache).is_err());
+        assert!(
+            Narinfo::parse(
+                &content(
+                    &path,
+                    "fixed:sha256:1kcsbgcx1f2z7qaj4a29zfa8ad7866f15hdbcds6kv92qf928fkw"
+                ),
+                &path,
+                &cache,
+            )
+            .is_err()
+        );
     }
 }
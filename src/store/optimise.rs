@@ -0,0 +1,173 @@
+use std::{
+    fs::{File, hard_link, read_dir, rename, symlink_metadata},
+    io::ErrorKind,
+    ops::AddAssign,
+    os::unix::fs::MetadataExt,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use tokio::task::spawn_blocking;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::store::{Store, canonicalise::replace_file};
+
+#[derive(Default)]
+pub struct OptimiseStats {
+    pub linked: u64,
+    pub saved: u64,
+}
+
+impl Store {
+    pub async fn optimise(&self) -> Result<()> {
+        let _lock = self.lock_gc_shared().await?;
+
+        // only registered store paths are guaranteed to be fully unpacked
+        let mut stats = OptimiseStats::default();
+        for path in self.valid_paths().await? {
+            let path = self.path.join(&path);
+            if path.symlink_metadata().is_err() {
+                continue;
+            }
+
+            let links = self.links.clone();
+            let tmp = self.tmp.clone();
+            stats += spawn_blocking(move || optimise_path(&links, &tmp, &path))
+                .await
+                .into_diagnostic()??;
+        }
+
+        info!(
+            "hard linked {} files, saving {:.2} MiB",
+            stats.linked,
+            stats.saved as f64 / (1024 * 1024) as f64,
+        );
+        Ok(())
+    }
+}
+
+impl AddAssign for OptimiseStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.linked += rhs.linked;
+        self.saved += rhs.saved;
+    }
+}
+
+// replace regular files in the given path with hard links to identical files under links,
+// similar to `nix-store --optimise`
+pub fn optimise_path(links: &Utf8Path, tmp: &Utf8Path, path: &Utf8Path) -> Result<OptimiseStats> {
+    let mut stats = OptimiseStats::default();
+    let mut paths = vec![path.to_owned()];
+
+    while let Some(path) = paths.pop() {
+        let metadata = symlink_metadata(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("failed to read {path}"))?;
+
+        if metadata.is_dir() {
+            for entry in read_dir(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to read {path}"))?
+            {
+                let entry = entry.into_diagnostic()?;
+                paths.push(Utf8PathBuf::try_from(entry.path()).into_diagnostic()?);
+            }
+        } else if metadata.is_file() {
+            // files with different permissions cannot share the same inode
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(if metadata.mode() & 0o111 == 0 {
+                b"-"
+            } else {
+                b"x"
+            });
+            File::open(&path)
+                .and_then(|file| hasher.update_reader(file).map(|_| ()))
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to read {path}"))?;
+            let link = links.join(hasher.finalize().to_hex().as_str());
+
+            match hard_link(&path, &link) {
+                Ok(()) => continue,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("failed to link {path} to {link}"));
+                }
+            }
+
+            if symlink_metadata(&link).into_diagnostic()?.ino() == metadata.ino() {
+                continue;
+            }
+
+            // link to a temporary path first, so the file is replaced atomically
+            let tmp = tmp.join(Uuid::new_v4().simple().to_string());
+            match hard_link(&link, &tmp) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::TooManyLinks => {
+                    debug!("{link} has too many links, skipping {path}");
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("failed to link {link} to {tmp}"));
+                }
+            }
+            replace_file(&path, |path| rename(&tmp, path))
+                .into_diagnostic()
+                .wrap_err_with(|| format!("failed to move {tmp} to {path}"))?;
+
+            stats.linked += 1;
+            if metadata.nlink() == 1 {
+                stats.saved += metadata.len();
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{Permissions, create_dir, set_permissions, write},
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+
+    use camino::Utf8Path;
+    use tempfile::TempDir;
+
+    use super::optimise_path;
+    use crate::store::Store;
+
+    #[test]
+    fn optimise() {
+        let tmp = TempDir::new().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let store = Store::new(root).unwrap();
+
+        for path in ["a", "b"] {
+            let path = store.path.join(path);
+            create_dir(&path).unwrap();
+            write(path.join("license"), "lorem ipsum").unwrap();
+            write(path.join("bin"), "lorem ipsum").unwrap();
+            set_permissions(path.join("bin"), Permissions::from_mode(0o555)).unwrap();
+        }
+
+        let a = optimise_path(&store.links, &store.tmp, &store.path.join("a")).unwrap();
+        assert_eq!(a.linked, 0);
+        let b = optimise_path(&store.links, &store.tmp, &store.path.join("b")).unwrap();
+        assert_eq!(b.linked, 2);
+        assert_eq!(b.saved, 22);
+
+        let ino = |path: &str| store.path.join(path).metadata().unwrap().ino();
+        assert_eq!(ino("a/license"), ino("b/license"));
+        assert_eq!(ino("a/bin"), ino("b/bin"));
+        assert_ne!(ino("a/license"), ino("a/bin"));
+
+        let b = optimise_path(&store.links, &store.tmp, &store.path.join("b")).unwrap();
+        assert_eq!(b.linked, 0);
+    }
+}
@@ -1,12 +1,17 @@
-use std::sync::{Arc, LazyLock};
+use std::{
+    fmt::Write,
+    sync::{Arc, LazyLock},
+};
 
 use camino::Utf8Path;
-use miette::{Result, bail, miette};
+use harmonia_store_core::store_path::{self, ContentAddress, StorePathName};
+use harmonia_utils_hash::{Algorithm, Sha256};
+use miette::{IntoDiagnostic, Result, bail, miette};
 use parse_display::Display;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize, Display, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[derive(Clone, Debug, Deserialize, Display, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct StorePath(Arc<str>);
 
 impl StorePath {
@@ -28,10 +33,62 @@ impl StorePath {
         }
     }
 
+    // the store path that a content address and references commit to, like makeFixedOutputPathFromCA in Nix
+    // references must be sorted and not include the store path itself
+    pub fn from_ca(
+        name: &str,
+        ca: &ContentAddress,
+        references: &[StorePath],
+        self_ref: bool,
+    ) -> Result<Self> {
+        let with_references = |ty: &str| {
+            let mut ty = ty.to_owned();
+            for path in references {
+                write!(ty, ":/nix/store/{path}").unwrap();
+            }
+            ty
+        };
+
+        let ty = match ca {
+            ContentAddress::Text(digest) if !self_ref => {
+                format!("{}:sha256:{digest:x}", with_references("text"))
+            }
+            ContentAddress::Recursive(hash) if hash.algorithm() == Algorithm::SHA256 => {
+                let mut ty = with_references("source");
+                if self_ref {
+                    ty.push_str(":self");
+                }
+                write!(ty, ":sha256:{hash:#x}").unwrap();
+                ty
+            }
+            ContentAddress::Flat(hash) | ContentAddress::Recursive(hash)
+                if references.is_empty() && !self_ref =>
+            {
+                let method = match ca {
+                    ContentAddress::Recursive(_) => "r:",
+                    _ => "",
+                };
+                let inner = Sha256::digest(format!("fixed:out:{method}{hash:x}"));
+                format!("output:out:sha256:{inner:x}")
+            }
+            _ => bail!("content address {ca} cannot have references"),
+        };
+
+        let name: StorePathName = name.parse().into_diagnostic()?;
+        let fingerprint = format!("{ty}:/nix/store:{name}");
+        Self::from_storeless(
+            store_path::StorePath::from_hash(&Sha256::digest(fingerprint), name).to_string(),
+        )
+    }
+
     pub fn hash(&self) -> &str {
         &self.0[.. 32]
     }
 
+    pub fn name(&self) -> &str {
+        &self.0[33 ..]
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
@@ -45,6 +102,9 @@ impl AsRef<Utf8Path> for StorePath {
 
 #[cfg(test)]
 mod tests {
+    use std::slice;
+
+    use harmonia_store_core::store_path::ContentAddress;
     use insta::assert_debug_snapshot;
 
     use super::StorePath;
@@ -67,4 +127,45 @@ mod tests {
         assert!(StorePath::new("/nix/store/hello-2.12.1").is_err());
         assert!(StorePath::from_storeless("hwz2l7ihv2skq7gr5l3paavs3rr9il7z").is_err());
     }
+
+    // test vectors from harmonia and Nix
+    #[test]
+    fn ca() {
+        let sha256 = "sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1";
+        let sha1 = "sha1:84983e441c3bd26ebaae4aa1f95129e5e54670f1";
+        let path = |ca: &str| {
+            StorePath::from_ca("konsole-18.12.3", &ca.parse().unwrap(), &[], false)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            path(&format!("text:{sha256}")),
+            "aidi01pgcl6i79fkw737qzx06kjl930m-konsole-18.12.3",
+        );
+        assert_eq!(
+            path(&format!("fixed:r:{sha256}")),
+            "1w01xxn8f7s9s4n65ry6rwd7x9awf04s-konsole-18.12.3",
+        );
+        assert_eq!(
+            path(&format!("fixed:r:{sha1}")),
+            "ag0y7g6rci9zsdz9nxcq5l1qllx3r99x-konsole-18.12.3",
+        );
+        assert_eq!(
+            path(&format!("fixed:{sha256}")),
+            "g9ngnw4w5vr9y3xkb7k2awl3mp95abrb-konsole-18.12.3",
+        );
+
+        let glibc =
+            StorePath::from_storeless("5m9amsvvh2z8sl7jrnc87hzy21glw6k1-glibc-2.40-66").unwrap();
+        let text: ContentAddress = format!("text:{sha256}").parse().unwrap();
+        let flat: ContentAddress = format!("fixed:{sha256}").parse().unwrap();
+        assert_ne!(
+            StorePath::from_ca("konsole-18.12.3", &text, slice::from_ref(&glibc), false)
+                .unwrap()
+                .to_string(),
+            path(&format!("text:{sha256}")),
+        );
+        assert!(StorePath::from_ca("konsole-18.12.3", &text, &[], true).is_err());
+        assert!(StorePath::from_ca("konsole-18.12.3", &flat, &[glibc], false).is_err());
+    }
 }
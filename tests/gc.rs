@@ -0,0 +1,55 @@
+mod utils;
+
+use std::fs::{create_dir_all, remove_file, write};
+
+use dir_diff::is_different;
+
+use crate::utils::TestEnv;
+
+const LIVE: &str = "xz8bzpl5fz92bkhcdmf4g45znvvzcakd-git-2.53.0";
+const DEAD: &str = "00000000000000000000000000000000-dead";
+
+#[test]
+fn gc() {
+    let env = TestEnv::new("basic");
+    env.command().arg("lock").assert().success();
+
+    let store = env.root().join("store");
+    create_dir_all(store.join(LIVE)).unwrap();
+    create_dir_all(store.join(DEAD)).unwrap();
+
+    env.command().arg("gc").assert().success();
+    assert!(store.join(LIVE).exists());
+    assert!(!store.join(DEAD).exists());
+    assert!(!is_different(env.fixture(), env.path()).unwrap());
+}
+
+#[test]
+fn gc_stale_root() {
+    let env = TestEnv::new("basic");
+    env.command().arg("lock").assert().success();
+    remove_file(env.path().join("unnix.lock.json")).unwrap();
+
+    let store = env.root().join("store");
+    create_dir_all(store.join(LIVE)).unwrap();
+
+    env.command().arg("gc").assert().success();
+    assert!(!store.join(LIVE).exists());
+    assert_eq!(env.root().join("gcroots").read_dir().unwrap().count(), 0);
+}
+
+#[test]
+fn gc_invalid_root() {
+    let env = TestEnv::new("basic");
+    env.command().arg("lock").assert().success();
+    write(env.path().join("unnix.lock.json"), "invalid").unwrap();
+
+    let store = env.root().join("store");
+    create_dir_all(store.join(LIVE)).unwrap();
+    create_dir_all(store.join(DEAD)).unwrap();
+
+    // the paths of the root can't be known, so nothing is deleted
+    env.command().arg("gc").assert().failure();
+    assert!(store.join(LIVE).exists());
+    assert!(store.join(DEAD).exists());
+}
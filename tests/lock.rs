@@ -1,5 +1,7 @@
 mod utils;
 
+use std::fs::remove_file;
+
 use dir_diff::is_different;
 
 use crate::utils::TestEnv;
@@ -24,3 +26,11 @@ fn devbox() {
     env.command().arg("lock").arg("--locked").assert().success();
     assert!(!is_different(env.fixture(), env.path()).unwrap());
 }
+
+#[test]
+fn r#static() {
+    let env = TestEnv::new("static");
+    remove_file(env.path().join("unnix.lock.json")).unwrap();
+    env.command().arg("lock").assert().success();
+    assert!(!is_different(env.fixture(), env.path()).unwrap());
+}
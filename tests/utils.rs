@@ -6,6 +6,7 @@ use tempfile::TempDir;
 
 pub struct TestEnv {
     fixture: PathBuf,
+    root: TempDir,
     tmp: TempDir,
 }
 
@@ -17,12 +18,17 @@ impl TestEnv {
             .join(fixture);
 
         copy(&fixture, tmp.path(), &CopyOptions::new().content_only(true)).unwrap();
-        Self { tmp, fixture }
+        Self {
+            fixture,
+            root: TempDir::new().unwrap(),
+            tmp,
+        }
     }
 
     pub fn command(&self) -> Command {
         let mut cmd = cargo_bin_cmd!();
         cmd.current_dir(self.path());
+        cmd.env("UNNIX_ROOT", self.root());
         cmd
     }
 
@@ -30,6 +36,10 @@ impl TestEnv {
         &self.fixture
     }
 
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
     pub fn path(&self) -> &Path {
         self.tmp.path()
     }